@@ -0,0 +1,337 @@
+//! Checkpoint and restart
+//!
+//! A checkpoint holds everything `driver::Simulation::step` depends on: the
+//! cell polygons, values, surfaces and other fields, the model time and step count, the stellar
+//! forcing and the run parameters. Every float is stored by its bits, so a restarted run
+//! continues exactly as the original would have.
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use super::{coords, driver, forcing, grid, integrate, pgen, surface};
+use super::error::Error;
+
+static MAGIC: &[u8; 8] = b"ISOSPHCK";
+static VERSION: u32 = 1;
+
+fn scheme_code(scheme: integrate::TimeScheme) -> u8 {
+    match scheme {
+        integrate::TimeScheme::ForwardEuler => 0,
+        integrate::TimeScheme::BackwardEuler => 1,
+        integrate::TimeScheme::Imex => 2,
+        integrate::TimeScheme::SspRk2 => 3,
+        integrate::TimeScheme::SspRk3 => 4,
+        integrate::TimeScheme::Rk4 => 5,
+    }
+}
+
+fn scheme_from_code(code: u8) -> Result<integrate::TimeScheme, Error> {
+    match code {
+        0 => Ok(integrate::TimeScheme::ForwardEuler),
+        1 => Ok(integrate::TimeScheme::BackwardEuler),
+        2 => Ok(integrate::TimeScheme::Imex),
+        3 => Ok(integrate::TimeScheme::SspRk2),
+        4 => Ok(integrate::TimeScheme::SspRk3),
+        5 => Ok(integrate::TimeScheme::Rk4),
+        _ => Err(Error::InvalidCheckpoint("unknown time scheme")),
+    }
+}
+
+fn length_scale_code(length_scale: pgen::LengthScale) -> u8 {
+    match length_scale {
+        pgen::LengthScale::MeanCellSize => 0,
+        pgen::LengthScale::MinCellSpacing => 1,
+    }
+}
+
+fn length_scale_from_code(code: u8) -> Result<pgen::LengthScale, Error> {
+    match code {
+        0 => Ok(pgen::LengthScale::MeanCellSize),
+        1 => Ok(pgen::LengthScale::MinCellSpacing),
+        _ => Err(Error::InvalidCheckpoint("unknown CFL length scale")),
+    }
+}
+
+struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    fn u8(self: &mut Encoder, x: u8) {
+        self.bytes.push(x);
+    }
+    fn u32(self: &mut Encoder, x: u32) {
+        self.bytes.extend_from_slice(&x.to_le_bytes());
+    }
+    fn u64(self: &mut Encoder, x: u64) {
+        self.bytes.extend_from_slice(&x.to_le_bytes());
+    }
+    fn f64(self: &mut Encoder, x: f64) {
+        self.u64(x.to_bits());
+    }
+    fn option(self: &mut Encoder, x: Option<f64>) {
+        match x {
+            Some(x) => { self.u8(1); self.f64(x) },
+            None => { self.u8(0); self.f64(0.0) },
+        }
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take<const N: usize>(self: &mut Decoder<'a>) -> Result<[u8; N], Error> {
+        if self.bytes.len() < N {
+            return Err(Error::InvalidCheckpoint("file is truncated"));
+        }
+        let (head, tail) = self.bytes.split_at(N);
+        self.bytes = tail;
+        Ok(head.try_into().expect("split at N"))
+    }
+    fn u8(self: &mut Decoder<'a>) -> Result<u8, Error> {
+        Ok(self.take::<1>()?[0])
+    }
+    fn u32(self: &mut Decoder<'a>) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+    fn u64(self: &mut Decoder<'a>) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+    fn f64(self: &mut Decoder<'a>) -> Result<f64, Error> {
+        Ok(f64::from_bits(self.u64()?))
+    }
+    fn option(self: &mut Decoder<'a>) -> Result<Option<f64>, Error> {
+        let present = self.u8()?;
+        let x = self.f64()?;
+        Ok((present == 1).then_some(x))
+    }
+}
+
+/// Write `sim` to `path`.
+///
+/// The checkpoint is written next to `path` first and then moved over it,
+/// so an interrupted write never destroys the previous checkpoint.
+pub fn write_checkpoint(sim: &driver::Simulation, path: &Path) -> std::io::Result<()> {
+    let mut e = Encoder { bytes: Vec::new() };
+    e.bytes.extend_from_slice(MAGIC);
+    e.u32(VERSION);
+    e.f64(sim.eps1);
+    e.f64(sim.eps2);
+    e.u8(scheme_code(sim.scheme));
+    e.f64(sim.timestep.courant_number);
+    e.option(sim.timestep.fixed_dt);
+    e.option(sim.timestep.max_dt);
+    e.option(sim.timestep.min_dt);
+    e.f64(sim.timestep.shrink_factor);
+    e.u8(length_scale_code(sim.timestep.length_scale));
+    e.f64(sim.time);
+    e.u64(sim.steps as u64);
+    e.option(sim.last_dt);
+    let (code, limit) = match sim.limiter {
+        None => (0, 0.0),
+        Some(pgen::CFL_Limiter::AdvectionLimited(dt)) => (1, dt),
+        Some(pgen::CFL_Limiter::DiffusionLimited(dt)) => (2, dt),
+        Some(pgen::CFL_Limiter::SourceLimited(dt)) => (3, dt),
+        Some(pgen::CFL_Limiter::NoLimit(dt)) => (4, dt),
+    };
+    e.u8(code);
+    e.f64(limit);
+    match sim.forcing {
+        forcing::Forcing::Fixed(light) => {
+            e.u8(0);
+            e.f64(light.substellar.phi);
+            e.f64(light.substellar.theta);
+            e.f64(light.scale);
+        },
+        forcing::Forcing::Orbit(orbit) => {
+            e.u8(1);
+            for x in [orbit.period, orbit.eccentricity, orbit.obliquity, orbit.periastron, orbit.true_anomaly] {
+                e.f64(x);
+            }
+        },
+    }
+    e.u8(match sim.insolation {
+        forcing::InsolationRule::Centroid => 0,
+        forcing::InsolationRule::Exact => 1,
+    });
+    e.u64(sim.network.cells.len() as u64);
+    for cell in sim.network.cells.iter() {
+        e.u32(cell.polygon.nodes.len() as u32);
+        for node in cell.polygon.nodes.iter() {
+            e.f64(node.phi);
+            e.f64(node.theta);
+        }
+        e.f64(cell.value);
+        e.f64(cell.surface.albedo);
+        e.f64(cell.surface.emissivity);
+        e.f64(cell.surface.heat_capacity);
+        let ice = cell.surface.ice;
+        e.u8(ice.is_some() as u8);
+        let ice = ice.unwrap_or(surface::IceAlbedo { albedo: 0.0, freeze: 0.0, width: 0.0 });
+        e.f64(ice.albedo);
+        e.f64(ice.freeze);
+        e.f64(ice.width);
+    }
+
+    e.u32(sim.network.n_fields() as u32 - 1);
+    for field in 1..sim.network.n_fields() {
+        let name = sim.network.field_name(field).as_bytes();
+        e.u32(name.len() as u32);
+        e.bytes.extend_from_slice(name);
+        for value in sim.network.values(field) {
+            e.f64(value);
+        }
+    }
+
+    let tmp = path.with_extension("tmp");
+    {
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        out.write_all(&e.bytes)?;
+        out.flush()?;
+    }
+    fs::rename(&tmp, path)
+}
+
+/// Read a simulation back from a checkpoint written by `write_checkpoint`
+pub fn read_checkpoint(path: &Path) -> Result<driver::Simulation, Error> {
+    let bytes = fs::read(path)?;
+    let mut d = Decoder { bytes: &bytes };
+    if &d.take::<8>()? != MAGIC {
+        return Err(Error::InvalidCheckpoint("not a checkpoint file"));
+    }
+    if d.u32()? != VERSION {
+        return Err(Error::InvalidCheckpoint("unsupported checkpoint version"));
+    }
+    let eps1 = d.f64()?;
+    let eps2 = d.f64()?;
+    let scheme = scheme_from_code(d.u8()?)?;
+    let timestep = driver::TimestepControl {
+        courant_number: d.f64()?,
+        fixed_dt: d.option()?,
+        max_dt: d.option()?,
+        min_dt: d.option()?,
+        shrink_factor: d.f64()?,
+        length_scale: length_scale_from_code(d.u8()?)?,
+    };
+    timestep.check()?;
+    let time = d.f64()?;
+    let steps = d.u64()? as usize;
+    let last_dt = d.option()?;
+    let limiter = match (d.u8()?, d.f64()?) {
+        (0, _) => None,
+        (1, dt) => Some(pgen::CFL_Limiter::AdvectionLimited(dt)),
+        (2, dt) => Some(pgen::CFL_Limiter::DiffusionLimited(dt)),
+        (3, dt) => Some(pgen::CFL_Limiter::SourceLimited(dt)),
+        (4, dt) => Some(pgen::CFL_Limiter::NoLimit(dt)),
+        _ => return Err(Error::InvalidCheckpoint("unknown stability limiter")),
+    };
+    let forcing = match d.u8()? {
+        0 => {
+            let substellar = coords::Coordinate::new(d.f64()?, d.f64()?)?;
+            forcing::Forcing::Fixed(forcing::Illumination { substellar, scale: d.f64()?, rule: Default::default() })
+        },
+        1 => forcing::Forcing::Orbit(forcing::Orbit {
+            period: d.f64()?,
+            eccentricity: d.f64()?,
+            obliquity: d.f64()?,
+            periastron: d.f64()?,
+            true_anomaly: d.f64()?,
+        }),
+        _ => return Err(Error::InvalidCheckpoint("unknown forcing")),
+    };
+    let insolation = match d.u8()? {
+        0 => forcing::InsolationRule::Centroid,
+        1 => forcing::InsolationRule::Exact,
+        _ => return Err(Error::InvalidCheckpoint("unknown insolation rule")),
+    };
+    let n_cells = d.u64()? as usize;
+    let mut cells = Vec::with_capacity(n_cells.min(bytes.len()));
+    for _ in 0..n_cells {
+        let n_nodes = d.u32()? as usize;
+        let mut nodes = Vec::with_capacity(n_nodes.min(bytes.len()));
+        for _ in 0..n_nodes {
+            let phi = d.f64()?;
+            let theta = d.f64()?;
+            nodes.push(coords::Coordinate::new(phi, theta)?);
+        }
+        let mut cell = grid::GridCell::new(coords::Polygon::new(nodes), d.f64()?)?;
+        cell.surface = surface::Surface { albedo: d.f64()?, emissivity: d.f64()?, heat_capacity: d.f64()?, ice: None };
+        let has_ice = d.u8()? == 1;
+        let ice = surface::IceAlbedo { albedo: d.f64()?, freeze: d.f64()?, width: d.f64()? };
+        cell.surface.ice = has_ice.then_some(ice);
+        cell.surface.check()?;
+        cells.push(cell);
+    }
+    let mut network = grid::GridNetwork::new(cells);
+    let n_fields = d.u32()?;
+    for _ in 0..n_fields {
+        let len = d.u32()? as usize;
+        if d.bytes.len() < len {
+            return Err(Error::InvalidCheckpoint("file is truncated"));
+        }
+        let (name, rest) = d.bytes.split_at(len);
+        d.bytes = rest;
+        let name = std::str::from_utf8(name).map_err(|_| Error::InvalidCheckpoint("field name is not UTF-8"))?;
+        let values = (0..n_cells).map(|_| d.f64()).collect::<Result<Vec<f64>, Error>>()?;
+        network.add_field(name, values)?;
+    }
+    if !d.bytes.is_empty() {
+        return Err(Error::InvalidCheckpoint("trailing data after the last field"));
+    }
+    let mut sim = driver::Simulation::new(network, eps1, eps2, scheme);
+    sim.timestep = timestep;
+    sim.forcing = forcing;
+    sim.insolation = insolation;
+    sim.time = time;
+    sim.steps = steps;
+    sim.last_dt = last_dt;
+    sim.limiter = limiter;
+    Ok(sim)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meshgen::MeshType;
+
+    #[test]
+    fn test_restart_is_exact() {
+        let path = std::env::temp_dir().join(format!("isosphere_checkpoint_{}.ckpt", std::process::id()));
+        let mut network = pgen::init_mesh(MeshType::Geodesic(1), pgen::InitialCondition::Radiative).unwrap();
+        surface::apply(&mut network, |lat, lon, s| *s = surface::Surface {
+            albedo: 0.3 * lat.cos(),
+            emissivity: 0.9,
+            heat_capacity: 1.5 + lon.sin(),
+            ice: (lat > 0.0).then_some(surface::IceAlbedo { albedo: 0.6, freeze: 0.7, width: 0.05 }),
+        }).unwrap();
+        let tracer = network.add_field("tracer", network.cells.iter().map(|c| c.center().phi).collect()).unwrap();
+        let mut sim = driver::Simulation::new(network, 1.0, 0.5, integrate::TimeScheme::SspRk2);
+        sim.timestep.max_dt = Some(0.01);
+        sim.forcing = forcing::Forcing::Orbit(forcing::Orbit { period: 0.1, eccentricity: 0.2, obliquity: 0.4, periastron: 1.0, true_anomaly: 0.5 });
+        sim.insolation = forcing::InsolationRule::Centroid;
+        let criteria = driver::StoppingCriteria { max_steps: Some(3), ..Default::default() };
+        sim.run(&criteria).unwrap();
+        write_checkpoint(&sim, &path).unwrap();
+        let saved = sim.network.values(tracer);
+        sim.run(&criteria).unwrap();
+
+        let mut restarted = read_checkpoint(&path).unwrap();
+        assert_eq!(restarted.steps, 3);
+        assert_eq!(restarted.forcing, sim.forcing);
+        assert_eq!(restarted.insolation, sim.insolation);
+        assert_eq!(restarted.network.field_name(tracer), "tracer");
+        assert_eq!(restarted.network.values(tracer), saved);
+        restarted.run(&criteria).unwrap();
+        assert_eq!(restarted.network.values(tracer), sim.network.values(tracer));
+        assert_eq!(restarted.steps, sim.steps);
+        assert_eq!(restarted.time.to_bits(), sim.time.to_bits());
+        for (a, b) in restarted.network.cells.iter().zip(sim.network.cells.iter()) {
+            assert_eq!(a.value.to_bits(), b.value.to_bits());
+            assert_eq!(a.surface, b.surface);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,425 @@
+//! Command-line interface
+//!
+//!
+
+use std::f64::consts::PI;
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+
+use isosphere::{coords, driver, forcing, integrate, meshgen, pgen, render, surface};
+
+use super::config;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Energy-balance models on spherical meshes")]
+pub struct Cli {
+    /// Logging level: off, error, warn, info, debug or trace [default: info]
+    #[arg(long, global = true)]
+    pub log_level: Option<log::LevelFilter>,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run a simulation and write the final cell values
+    Run(Box<RunArgs>),
+    /// Generate a mesh and write it out
+    Mesh {
+        #[command(flatten)]
+        mesh: MeshArgs,
+        /// Where to write the mesh: .vtu, .obj, .ply, a .png or .ppm map of the cells, otherwise CSV
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Print statistics about a mesh
+    Info {
+        #[command(flatten)]
+        mesh: MeshArgs,
+    },
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MeshKind {
+    Centroid,
+    Geodesic,
+    Goldberg,
+    LatLon,
+}
+
+#[derive(Args, Debug)]
+pub struct MeshArgs {
+    /// Kind of mesh
+    #[arg(long, value_enum, default_value_t = MeshKind::Centroid)]
+    pub mesh: MeshKind,
+    /// Subdivision level of the icosahedral meshes
+    #[arg(short = 'n', long, default_value_t = 3)]
+    pub level: u32,
+    /// Latitude bands of the lat-lon mesh
+    #[arg(long, default_value_t = 18)]
+    pub n_lat: u32,
+    /// Longitude bands of the lat-lon mesh
+    #[arg(long, default_value_t = 36)]
+    pub n_lon: u32,
+    /// Read the mesh from an .obj or .ply file instead of generating it
+    #[arg(long)]
+    pub mesh_file: Option<PathBuf>,
+}
+
+impl MeshArgs {
+    pub fn to_config(self: &MeshArgs) -> config::MeshConfig {
+        config::MeshConfig { kind: self.mesh, level: self.level, n_lat: self.n_lat, n_lon: self.n_lon, file: self.mesh_file.clone() }
+    }
+    pub fn mesh_type(self: &MeshArgs) -> meshgen::MeshType {
+        self.to_config().mesh_type()
+    }
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum InitialKind {
+    /// Every cell starts at `--initial-value`
+    Constant,
+    /// Local radiative equilibrium
+    Radiative,
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SchemeKind {
+    ForwardEuler,
+    BackwardEuler,
+    Imex,
+    SspRk2,
+    SspRk3,
+    Rk4,
+}
+
+impl From<SchemeKind> for integrate::TimeScheme {
+    fn from(kind: SchemeKind) -> integrate::TimeScheme {
+        match kind {
+            SchemeKind::ForwardEuler => integrate::TimeScheme::ForwardEuler,
+            SchemeKind::BackwardEuler => integrate::TimeScheme::BackwardEuler,
+            SchemeKind::Imex => integrate::TimeScheme::Imex,
+            SchemeKind::SspRk2 => integrate::TimeScheme::SspRk2,
+            SchemeKind::SspRk3 => integrate::TimeScheme::SspRk3,
+            SchemeKind::Rk4 => integrate::TimeScheme::Rk4,
+        }
+    }
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum InsolationKind {
+    /// Flux at the cell centroid times the area
+    #[default]
+    Centroid,
+    /// Exact integral over the lit part of each cell
+    Exact,
+}
+
+impl From<InsolationKind> for forcing::InsolationRule {
+    fn from(kind: InsolationKind) -> forcing::InsolationRule {
+        match kind {
+            InsolationKind::Centroid => forcing::InsolationRule::Centroid,
+            InsolationKind::Exact => forcing::InsolationRule::Exact,
+        }
+    }
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ColormapKind {
+    Viridis,
+    Inferno,
+    /// Blue through grey to red
+    Diverging,
+}
+
+impl From<ColormapKind> for render::Colormap {
+    fn from(kind: ColormapKind) -> render::Colormap {
+        match kind {
+            ColormapKind::Viridis => render::Colormap::Viridis,
+            ColormapKind::Inferno => render::Colormap::Inferno,
+            ColormapKind::Diverging => render::Colormap::Diverging,
+        }
+    }
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProjectionKind {
+    Equirectangular,
+    /// Orthographic view of the dayside hemisphere
+    Substellar,
+    /// Orthographic view of the nightside hemisphere
+    Antistellar,
+    /// Orthographic view from above the north pole
+    NorthPole,
+    /// Orthographic view from above the south pole
+    SouthPole,
+    Mollweide,
+    Robinson,
+    HammerAitoff,
+}
+
+impl ProjectionKind {
+    /// The projection, with the substellar and antistellar views centered on `substellar`
+    pub fn projection(self: ProjectionKind, substellar: &coords::Coordinate) -> render::Projection {
+        let orthographic = |phi: f64, theta: f64| render::Projection::Orthographic(coords::Coordinate { phi, theta });
+        match self {
+            ProjectionKind::Equirectangular => render::Projection::Equirectangular,
+            ProjectionKind::Substellar => orthographic(substellar.phi, substellar.theta),
+            ProjectionKind::Antistellar => orthographic(substellar.phi + PI, PI - substellar.theta),
+            ProjectionKind::NorthPole => orthographic(0.0, 0.0),
+            ProjectionKind::SouthPole => orthographic(0.0, PI),
+            ProjectionKind::Mollweide => render::Projection::Mollweide,
+            ProjectionKind::Robinson => render::Projection::Robinson,
+            ProjectionKind::HammerAitoff => render::Projection::HammerAitoff,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct RunArgs {
+    /// Read the whole run from a TOML or JSON configuration file instead of the options below
+    #[arg(long, exclusive = true)]
+    pub config: Option<PathBuf>,
+    #[command(flatten)]
+    pub mesh: MeshArgs,
+    /// Advection strength
+    #[arg(long, default_value_t = 1.0)]
+    pub eps1: f64,
+    /// Diffusion strength
+    #[arg(long, default_value_t = 1.0)]
+    pub eps2: f64,
+    /// Latitude of the fixed substellar point in degrees
+    #[arg(long)]
+    pub substellar_lat: Option<f64>,
+    /// Longitude of the fixed substellar point in degrees
+    #[arg(long)]
+    pub substellar_lon: Option<f64>,
+    /// Move the substellar point along an orbit of this period instead
+    #[arg(long, conflicts_with_all = ["substellar_lat", "substellar_lon"])]
+    pub orbital_period: Option<f64>,
+    /// Eccentricity of the orbit
+    #[arg(long, requires = "orbital_period", default_value_t = 0.0)]
+    pub eccentricity: f64,
+    /// Tilt of the spin axis from the orbit normal in degrees
+    #[arg(long, requires = "orbital_period", default_value_t = 0.0)]
+    pub obliquity: f64,
+    /// Angle along the orbit from the northern spring equinox to periastron in degrees
+    #[arg(long, requires = "orbital_period", default_value_t = 0.0)]
+    pub periastron: f64,
+    /// True anomaly at the start of the run in degrees
+    #[arg(long, requires = "orbital_period", default_value_t = 0.0)]
+    pub true_anomaly: f64,
+    /// How the starlight is integrated over each cell
+    #[arg(long, value_enum, default_value_t = InsolationKind::Centroid)]
+    pub insolation: InsolationKind,
+    /// Fraction of the starlight every cell reflects
+    #[arg(long, default_value_t = 0.0)]
+    pub albedo: f64,
+    /// Thermal emission of every cell relative to a black body
+    #[arg(long, default_value_t = 1.0)]
+    pub emissivity: f64,
+    /// Heat capacity of every cell
+    #[arg(long, default_value_t = 1.0)]
+    pub heat_capacity: f64,
+    /// CSV map of surface properties with lon and lat columns, overriding the uniform values
+    #[arg(long)]
+    pub surface_map: Option<PathBuf>,
+    /// Let every cell freeze over below `--freezing-point`, taking this albedo
+    #[arg(long, requires = "freezing_point")]
+    pub ice_albedo: Option<f64>,
+    /// Value at which a cell is half frozen
+    #[arg(long, requires = "ice_albedo")]
+    pub freezing_point: Option<f64>,
+    /// Width of the smooth ramp from open surface to ice; 0 switches abruptly
+    #[arg(long, requires = "ice_albedo", default_value_t = 0.0)]
+    pub ice_ramp: f64,
+    /// Initial condition
+    #[arg(long, value_enum, default_value_t = InitialKind::Constant)]
+    pub initial: InitialKind,
+    /// Starting value of every cell for a constant initial condition
+    #[arg(long, default_value_t = 0.0)]
+    pub initial_value: f64,
+    /// Time integration scheme
+    #[arg(long, value_enum, default_value_t = SchemeKind::ForwardEuler)]
+    pub scheme: SchemeKind,
+    /// Safety factor on the explicit stability limits
+    #[arg(long, default_value_t = pgen::COURANT_NUMBER)]
+    pub courant: f64,
+    /// Use this timestep instead of the stability limit
+    #[arg(long)]
+    pub dt: Option<f64>,
+    /// Largest timestep to take
+    #[arg(long)]
+    pub max_dt: Option<f64>,
+    /// Smallest timestep to shrink a failing step to before giving up
+    #[arg(long)]
+    pub min_dt: Option<f64>,
+    /// Stop once the energy imbalance in percent, or the absolute imbalance without starlight, is below this
+    #[arg(long)]
+    pub energy_tolerance: Option<f64>,
+    /// Stop once no cell changes by more than this in one step
+    #[arg(long)]
+    pub max_change: Option<f64>,
+    /// Stop at this model time
+    #[arg(long)]
+    pub max_time: Option<f64>,
+    /// Stop after this many steps. Defaults to 100 if no other criterion is given.
+    #[arg(long)]
+    pub max_steps: Option<usize>,
+    /// Where to write the final cell values
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+    /// Write a ParaView time series of the run to this .pvd file
+    #[arg(long)]
+    pub vtk: Option<PathBuf>,
+    /// Write the same time series to this CF/UGRID NetCDF file
+    #[arg(long)]
+    pub netcdf: Option<PathBuf>,
+    /// Snapshot interval in steps for `--output`, `--vtk` and `--netcdf`
+    #[arg(long)]
+    pub every: Option<usize>,
+    /// Save the run to this checkpoint file at the end and on Ctrl-C
+    #[arg(long)]
+    pub checkpoint: Option<PathBuf>,
+    /// Also checkpoint every this many steps
+    #[arg(long, requires = "checkpoint")]
+    pub checkpoint_every: Option<usize>,
+    /// Continue the run saved in this checkpoint; its mesh, physics, forcing and time settings are used
+    #[arg(long)]
+    pub restart: Option<PathBuf>,
+    /// Draw the final cell values on a map and write it to this .png or .ppm file
+    #[arg(long)]
+    pub map: Option<PathBuf>,
+    /// Width of the map in pixels
+    #[arg(long, default_value_t = 720)]
+    pub map_width: u32,
+    /// Projection of the map
+    #[arg(long, value_enum, default_value_t = ProjectionKind::Equirectangular)]
+    pub projection: ProjectionKind,
+    /// Colormap of the map
+    #[arg(long, value_enum, default_value_t = ColormapKind::Viridis)]
+    pub colormap: ColormapKind,
+    /// Draw the cell edges on the map
+    #[arg(long)]
+    pub map_edges: bool,
+    /// Print an ASCII heatmap of the final cell values
+    #[arg(long)]
+    pub ascii_map: bool,
+    /// Sweep the stellar flux up from this scale and back, running to the stopping criteria at each step
+    #[arg(long, requires = "sweep_max", conflicts_with = "orbital_period")]
+    pub sweep_min: Option<f64>,
+    /// Largest stellar flux of the sweep
+    #[arg(long, requires = "sweep_min")]
+    pub sweep_max: Option<f64>,
+    /// Number of stellar fluxes on the way up
+    #[arg(long, requires = "sweep_min", default_value_t = 11)]
+    pub sweep_steps: usize,
+    /// Write the hysteresis loop of the sweep to this CSV file instead of printing it
+    #[arg(long, requires = "sweep_min")]
+    pub sweep_output: Option<PathBuf>,
+}
+
+impl RunArgs {
+    pub fn stopping_criteria(self: &RunArgs) -> driver::StoppingCriteria {
+        let mut criteria = driver::StoppingCriteria {
+            energy_tolerance: self.energy_tolerance,
+            max_change: self.max_change,
+            max_time: self.max_time,
+            max_steps: self.max_steps,
+        };
+        if criteria.is_empty() {
+            criteria.max_steps = Some(100);
+        }
+        criteria
+    }
+    /// The configuration given by `--config`, or the one equivalent to the other options
+    pub fn to_config(self: &RunArgs) -> Result<config::RunConfig, String> {
+        if let Some(path) = &self.config {
+            return config::RunConfig::load(path);
+        }
+        Ok(config::RunConfig {
+            mesh: self.mesh.to_config(),
+            physics: config::PhysicsConfig { eps1: self.eps1, eps2: self.eps2, courant_number: self.courant },
+            forcing: config::ForcingConfig {
+                substellar_lat: self.substellar_lat.unwrap_or(0.0),
+                substellar_lon: self.substellar_lon.unwrap_or(0.0),
+                orbit: self.orbital_period.map(|period| config::OrbitConfig {
+                    period,
+                    eccentricity: self.eccentricity,
+                    obliquity: self.obliquity,
+                    periastron: self.periastron,
+                    true_anomaly: self.true_anomaly,
+                }),
+                insolation: self.insolation,
+            },
+            surface: config::SurfaceConfig {
+                albedo: self.albedo,
+                emissivity: self.emissivity,
+                heat_capacity: self.heat_capacity,
+                ice: self.ice_albedo.zip(self.freezing_point).map(|(albedo, freeze)| surface::IceAlbedo { albedo, freeze, width: self.ice_ramp }),
+                map: self.surface_map.clone(),
+                region: Vec::new(),
+            },
+            initial: config::InitialConfig { kind: self.initial, value: self.initial_value },
+            time: config::TimeConfig { scheme: self.scheme, dt: self.dt, max_dt: self.max_dt, min_dt: self.min_dt },
+            stop: self.stopping_criteria(),
+            output: config::OutputConfig {
+                path: self.output.clone(),
+                every: self.every,
+                vtk: self.vtk.clone(),
+                netcdf: self.netcdf.clone(),
+                checkpoint: self.checkpoint.clone(),
+                checkpoint_every: self.checkpoint_every,
+            },
+            map: config::MapConfig {
+                path: self.map.clone(),
+                width: self.map_width,
+                projection: self.projection,
+                colormap: self.colormap,
+                edges: self.map_edges,
+                ascii: self.ascii_map,
+            },
+            sweep: self.sweep_min.zip(self.sweep_max).map(|(min, max)| config::SweepConfig {
+                min,
+                max,
+                steps: self.sweep_steps,
+                path: self.sweep_output.clone(),
+            }),
+            restart: self.restart.clone(),
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_run() {
+        let cli = Cli::parse_from(["isosphere", "run", "--mesh", "lat-lon", "--n-lat", "9", "--eps2", "0.5",
+            "--initial", "radiative", "--scheme", "ssp-rk3", "--max-time", "2.0", "--dt", "0.01", "-o", "out.csv"]);
+        let Command::Run(args) = cli.command else { panic!("expected the run subcommand") };
+        assert_eq!(args.mesh.mesh_type(), meshgen::MeshType::LatLon(9, 36));
+        assert_eq!(args.eps2, 0.5);
+        assert_eq!(integrate::TimeScheme::from(args.scheme), integrate::TimeScheme::SspRk3);
+        let criteria = args.stopping_criteria();
+        assert_eq!(criteria.max_time, Some(2.0));
+        assert_eq!(criteria.max_steps, None);
+        let config = args.to_config().unwrap();
+        assert!(matches!(config.initial.initial_condition(), pgen::InitialCondition::Radiative));
+        assert_eq!(config.timestep_control().unwrap().fixed_dt, Some(0.01));
+
+        let cli = Cli::parse_from(["isosphere", "run"]);
+        let Command::Run(args) = cli.command else { panic!("expected the run subcommand") };
+        assert_eq!(args.stopping_criteria().max_steps, Some(100));
+    }
+}
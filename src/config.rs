@@ -0,0 +1,563 @@
+//! Run configuration files
+//!
+//! A whole simulation can be described in a TOML or JSON file, chosen by
+//! extension. Every field has a default, so a file only needs the values
+//! that differ from it:
+//!
+//! ```toml
+//! log_level = "info"
+//!
+//! [mesh]
+//! type = "geodesic"
+//! level = 4
+//!
+//! [physics]
+//! eps1 = 1.0
+//! eps2 = 0.5
+//! courant_number = 0.4
+//!
+//! [forcing]
+//! insolation = "exact"
+//!
+//! [forcing.orbit]
+//! period = 50.0
+//! eccentricity = 0.1
+//! obliquity = 23.4
+//!
+//! [surface]
+//! albedo = 0.3
+//!
+//! [surface.ice]
+//! albedo = 0.6
+//! freeze = 0.7
+//! width = 0.02
+//!
+//! [[surface.region]]
+//! lat_min = 70.0
+//! albedo = 0.6
+//!
+//! [initial]
+//! type = "radiative"
+//!
+//! [time]
+//! scheme = "ssp-rk3"
+//! max_dt = 0.01
+//!
+//! [stop]
+//! energy_tolerance = 0.1
+//! max_steps = 10000
+//!
+//! [output]
+//! path = "final.csv"
+//! every = 100
+//! vtk = "run.pvd"
+//! netcdf = "run.nc"
+//! checkpoint = "run.ckpt"
+//! checkpoint_every = 1000
+//!
+//! [map]
+//! path = "final.png"
+//! projection = "mollweide"
+//! colormap = "inferno"
+//! edges = true
+//! ```
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use isosphere::{checkpoint, coords, driver, forcing, grid, hysteresis, integrate, meshgen, meshio, pgen, render, surface};
+use log::info;
+use isosphere::Error;
+
+use super::cli;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MeshConfig {
+    #[serde(rename = "type")]
+    pub kind: cli::MeshKind,
+    /// Subdivision level of the icosahedral meshes
+    pub level: u32,
+    /// Latitude bands of the lat-lon mesh
+    pub n_lat: u32,
+    /// Longitude bands of the lat-lon mesh
+    pub n_lon: u32,
+    /// Read the mesh from this `.obj` or `.ply` file instead of generating it
+    pub file: Option<PathBuf>,
+}
+
+impl Default for MeshConfig {
+    fn default() -> MeshConfig {
+        MeshConfig { kind: cli::MeshKind::Centroid, level: 3, n_lat: 18, n_lon: 36, file: None }
+    }
+}
+
+impl MeshConfig {
+    pub fn mesh_type(self: &MeshConfig) -> meshgen::MeshType {
+        match self.kind {
+            cli::MeshKind::Centroid => meshgen::MeshType::Centroid(self.level),
+            cli::MeshKind::Geodesic => meshgen::MeshType::Geodesic(self.level),
+            cli::MeshKind::Goldberg => meshgen::MeshType::Goldberg(self.level),
+            cli::MeshKind::LatLon => meshgen::MeshType::LatLon(self.n_lat, self.n_lon),
+        }
+    }
+    /// Build the mesh, reading it from `file` if given, and set up `initial` on it under `light`
+    pub fn network(self: &MeshConfig, initial: pgen::InitialCondition, light: &forcing::Illumination) -> Result<grid::GridNetwork, Error> {
+        let polygons = match &self.file {
+            Some(path) => meshio::read_mesh(path)?,
+            None => self.mesh_type().generate()?,
+        };
+        pgen::init_network(polygons, initial, light)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PhysicsConfig {
+    /// Advection strength
+    pub eps1: f64,
+    /// Diffusion strength
+    pub eps2: f64,
+    /// Safety factor on the explicit stability limits
+    pub courant_number: f64,
+}
+
+impl Default for PhysicsConfig {
+    fn default() -> PhysicsConfig {
+        PhysicsConfig { eps1: 1.0, eps2: 1.0, courant_number: pgen::COURANT_NUMBER }
+    }
+}
+
+/// An orbit for the substellar point to follow. Angles are in degrees.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct OrbitConfig {
+    /// Model time of one orbit
+    pub period: f64,
+    #[serde(default)]
+    pub eccentricity: f64,
+    /// Tilt of the spin axis from the orbit normal
+    #[serde(default)]
+    pub obliquity: f64,
+    /// Angle along the orbit from the northern spring equinox to periastron
+    #[serde(default)]
+    pub periastron: f64,
+    /// True anomaly at the start of the run
+    #[serde(default)]
+    pub true_anomaly: f64,
+}
+
+/// Where the star is overhead. Angles are in degrees.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForcingConfig {
+    /// Fixed substellar latitude, used without an orbit
+    pub substellar_lat: f64,
+    /// Fixed substellar longitude, used without an orbit
+    pub substellar_lon: f64,
+    /// Move the substellar point along this orbit instead
+    pub orbit: Option<OrbitConfig>,
+    /// How the starlight is integrated over each cell
+    pub insolation: cli::InsolationKind,
+}
+
+impl ForcingConfig {
+    pub fn forcing(self: &ForcingConfig) -> Result<forcing::Forcing, Error> {
+        match &self.orbit {
+            Some(orbit) => Ok(forcing::Forcing::Orbit(forcing::Orbit {
+                period: orbit.period,
+                eccentricity: orbit.eccentricity,
+                obliquity: orbit.obliquity.to_radians(),
+                periastron: orbit.periastron.to_radians(),
+                true_anomaly: orbit.true_anomaly.to_radians(),
+            })),
+            None => Ok(forcing::Forcing::Fixed(forcing::Illumination::overhead(self.substellar_lat.to_radians(), self.substellar_lon.to_radians())?)),
+        }
+    }
+}
+
+/// A latitude-longitude box, in degrees, whose cells take the given surface properties
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegionConfig {
+    pub lat_min: f64,
+    pub lat_max: f64,
+    /// Western edge; a box with `lon_min > lon_max` crosses the antimeridian
+    pub lon_min: f64,
+    pub lon_max: f64,
+    pub albedo: Option<f64>,
+    pub emissivity: Option<f64>,
+    pub heat_capacity: Option<f64>,
+}
+
+impl Default for RegionConfig {
+    fn default() -> RegionConfig {
+        RegionConfig { lat_min: -90.0, lat_max: 90.0, lon_min: -180.0, lon_max: 180.0, albedo: None, emissivity: None, heat_capacity: None }
+    }
+}
+
+impl RegionConfig {
+    fn contains(self: &RegionConfig, lat: f64, lon: f64) -> bool {
+        let (lat, lon) = (lat.to_degrees(), (lon.to_degrees() + 180.0).rem_euclid(360.0) - 180.0);
+        let in_lon = if self.lon_min <= self.lon_max { (self.lon_min..=self.lon_max).contains(&lon) } else { lon >= self.lon_min || lon <= self.lon_max };
+        (self.lat_min..=self.lat_max).contains(&lat) && in_lon
+    }
+}
+
+/// Surface properties of the cells: uniform values, then a map file, then
+/// each region in order, each overriding the ones before
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SurfaceConfig {
+    pub albedo: f64,
+    pub emissivity: f64,
+    pub heat_capacity: f64,
+    /// Let every cell freeze over, switching its albedo to that of ice
+    pub ice: Option<surface::IceAlbedo>,
+    /// CSV map read by `surface::read_map`
+    pub map: Option<PathBuf>,
+    pub region: Vec<RegionConfig>,
+}
+
+impl Default for SurfaceConfig {
+    fn default() -> SurfaceConfig {
+        let surface = surface::Surface::default();
+        SurfaceConfig { albedo: surface.albedo, emissivity: surface.emissivity, heat_capacity: surface.heat_capacity, ice: surface.ice, map: None, region: Vec::new() }
+    }
+}
+
+impl SurfaceConfig {
+    pub fn apply(self: &SurfaceConfig, network: &mut grid::GridNetwork) -> Result<(), Error> {
+        let uniform = surface::Surface { albedo: self.albedo, emissivity: self.emissivity, heat_capacity: self.heat_capacity, ice: self.ice };
+        surface::apply(network, |_, _, s| *s = uniform)?;
+        if let Some(path) = &self.map {
+            surface::read_map(path)?.apply(network)?;
+        }
+        for region in self.region.iter() {
+            surface::apply(network, |lat, lon, s| {
+                if region.contains(lat, lon) {
+                    s.albedo = region.albedo.unwrap_or(s.albedo);
+                    s.emissivity = region.emissivity.unwrap_or(s.emissivity);
+                    s.heat_capacity = region.heat_capacity.unwrap_or(s.heat_capacity);
+                }
+            })?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct InitialConfig {
+    #[serde(rename = "type")]
+    pub kind: cli::InitialKind,
+    /// Starting value of every cell for a constant initial condition
+    pub value: f64,
+}
+
+impl Default for InitialConfig {
+    fn default() -> InitialConfig {
+        InitialConfig { kind: cli::InitialKind::Constant, value: 0.0 }
+    }
+}
+
+impl InitialConfig {
+    pub fn initial_condition(self: &InitialConfig) -> pgen::InitialCondition {
+        match self.kind {
+            cli::InitialKind::Constant => pgen::InitialCondition::Constant(self.value),
+            cli::InitialKind::Radiative => pgen::InitialCondition::Radiative,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeConfig {
+    pub scheme: cli::SchemeKind,
+    /// Use this timestep instead of the stability limit
+    pub dt: Option<f64>,
+    pub max_dt: Option<f64>,
+    pub min_dt: Option<f64>,
+}
+
+impl Default for TimeConfig {
+    fn default() -> TimeConfig {
+        TimeConfig { scheme: cli::SchemeKind::ForwardEuler, dt: None, max_dt: None, min_dt: None }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    /// Where to write the final cell values
+    pub path: Option<PathBuf>,
+    /// Also write a snapshot every this many steps, next to `path`
+    pub every: Option<usize>,
+    /// Write a ParaView time series to this `.pvd` file: the initial state,
+    /// one snapshot every `every` steps and the final state
+    pub vtk: Option<PathBuf>,
+    /// Write the same time series as a CF/UGRID NetCDF file
+    pub netcdf: Option<PathBuf>,
+    /// Save the run to this checkpoint file at the end, on Ctrl-C and every `checkpoint_every` steps
+    pub checkpoint: Option<PathBuf>,
+    pub checkpoint_every: Option<usize>,
+}
+
+impl OutputConfig {
+    /// Path of the snapshot taken after `step` steps: `path` with the step number added to its stem
+    pub fn snapshot_path(self: &OutputConfig, step: usize) -> Option<PathBuf> {
+        let path = self.path.as_ref()?;
+        let stem = path.file_stem()?.to_string_lossy();
+        let name = match path.extension() {
+            Some(ext) => format!("{}_{:06}.{}", stem, step, ext.to_string_lossy()),
+            None => format!("{}_{:06}", stem, step),
+        };
+        Some(path.with_file_name(name))
+    }
+}
+
+/// Sweep of the stellar flux for `hysteresis::sweep`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SweepConfig {
+    /// Smallest and largest scale of the fixed stellar flux
+    pub min: f64,
+    pub max: f64,
+    /// Number of fluxes on the way up, the same ones are visited on the way down
+    #[serde(default = "SweepConfig::default_steps")]
+    pub steps: usize,
+    /// Where to write the loop as CSV; it is printed if not given
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+}
+
+impl SweepConfig {
+    fn default_steps() -> usize {
+        11
+    }
+    pub fn scales(self: &SweepConfig) -> Vec<f64> {
+        hysteresis::flux_steps(self.min, self.max, self.steps)
+    }
+}
+
+/// Maps of the final cell values
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MapConfig {
+    /// Write a map to this `.png` or `.ppm` file
+    pub path: Option<PathBuf>,
+    /// Width of the map in pixels
+    pub width: u32,
+    pub projection: cli::ProjectionKind,
+    pub colormap: cli::ColormapKind,
+    /// Draw the cell edges over the values
+    pub edges: bool,
+    /// Print an ASCII heatmap to the terminal
+    pub ascii: bool,
+}
+
+impl Default for MapConfig {
+    fn default() -> MapConfig {
+        MapConfig { path: None, width: 720, projection: cli::ProjectionKind::Equirectangular, colormap: cli::ColormapKind::Viridis, edges: false, ascii: false }
+    }
+}
+
+impl MapConfig {
+    /// How to draw the map, with the substellar and antistellar views centered on `substellar`
+    pub fn options(self: &MapConfig, substellar: &coords::Coordinate) -> render::MapOptions {
+        render::MapOptions { width: self.width, projection: self.projection.projection(substellar), colormap: self.colormap.into(), edges: self.edges, range: None }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RunConfig {
+    pub mesh: MeshConfig,
+    pub physics: PhysicsConfig,
+    pub forcing: ForcingConfig,
+    pub surface: SurfaceConfig,
+    pub initial: InitialConfig,
+    pub time: TimeConfig,
+    pub stop: driver::StoppingCriteria,
+    pub output: OutputConfig,
+    pub map: MapConfig,
+    /// Sweep the stellar flux instead of running once at a fixed flux
+    pub sweep: Option<SweepConfig>,
+    /// Continue the run saved in this checkpoint instead of starting a new one
+    pub restart: Option<PathBuf>,
+    /// off, error, warn, info, debug or trace
+    pub log_level: String,
+}
+
+impl Default for RunConfig {
+    fn default() -> RunConfig {
+        RunConfig {
+            mesh: MeshConfig::default(),
+            physics: PhysicsConfig::default(),
+            forcing: ForcingConfig::default(),
+            surface: SurfaceConfig::default(),
+            initial: InitialConfig::default(),
+            time: TimeConfig::default(),
+            stop: driver::StoppingCriteria { max_steps: Some(100), ..Default::default() },
+            output: OutputConfig::default(),
+            map: MapConfig::default(),
+            sweep: None,
+            restart: None,
+            log_level: String::from("info"),
+        }
+    }
+}
+
+impl RunConfig {
+    /// Read a configuration from a `.toml` or `.json` file
+    pub fn load(path: &Path) -> Result<RunConfig, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => RunConfig::from_toml(&text),
+            Some("json") => RunConfig::from_json(&text),
+            _ => Err(format!("Unknown configuration format for {}; expected .toml or .json", path.display())),
+        }
+    }
+    pub fn from_toml(text: &str) -> Result<RunConfig, String> {
+        toml::from_str(text).map_err(|e| format!("Invalid TOML configuration: {}", e))
+    }
+    pub fn from_json(text: &str) -> Result<RunConfig, String> {
+        serde_json::from_str(text).map_err(|e| format!("Invalid JSON configuration: {}", e))
+    }
+    pub fn log_level(self: &RunConfig) -> Result<log::LevelFilter, String> {
+        self.log_level.parse().map_err(|_| format!("Unknown log level {}", self.log_level))
+    }
+    pub fn scheme(self: &RunConfig) -> integrate::TimeScheme {
+        self.time.scheme.into()
+    }
+    /// Build the mesh and the simulation this configuration describes,
+    /// or read it back from the `restart` checkpoint
+    pub fn simulation(self: &RunConfig) -> Result<driver::Simulation, Error> {
+        if let Some(path) = &self.restart {
+            let sim = checkpoint::read_checkpoint(path)?;
+            info!("Restarting from {} at step {}, time {}; the mesh, physics, forcing and time settings are taken from the checkpoint", path.display(), sim.steps, sim.time);
+            return Ok(sim);
+        }
+        let forcing = self.forcing.forcing()?;
+        let mut network = self.mesh.network(self.initial.initial_condition(), &forcing.illumination(0.0)?)?;
+        self.surface.apply(&mut network)?;
+        let mut sim = driver::Simulation::new(network, self.physics.eps1, self.physics.eps2, self.scheme());
+        sim.timestep = self.timestep_control()?;
+        sim.forcing = forcing;
+        sim.insolation = self.forcing.insolation.into();
+        Ok(sim)
+    }
+    pub fn timestep_control(self: &RunConfig) -> Result<driver::TimestepControl, Error> {
+        // lat-lon cells narrow towards the poles, and a mesh read from a file may be just as uneven
+        let length_scale = if self.mesh.file.is_some() || self.mesh.kind == cli::MeshKind::LatLon {
+            pgen::LengthScale::MinCellSpacing
+        } else {
+            pgen::LengthScale::MeanCellSize
+        };
+        let control = driver::TimestepControl {
+            courant_number: self.physics.courant_number,
+            fixed_dt: self.time.dt,
+            max_dt: self.time.max_dt,
+            min_dt: self.time.min_dt,
+            length_scale,
+            ..Default::default()
+        };
+        control.check()?;
+        Ok(control)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::FRAC_PI_2;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let toml = r#"
+            [mesh]
+            type = "lat-lon"
+            n_lat = 9
+
+            [physics]
+            eps2 = 0.5
+            courant_number = 0.2
+
+            [forcing]
+            substellar_lat = 30.0
+            insolation = "exact"
+
+            [surface]
+            heat_capacity = 2.0
+
+            [[surface.region]]
+            lat_min = 65.0
+            albedo = 0.6
+
+            [[surface.region]]
+            lon_min = 170.0
+            lon_max = -170.0
+            emissivity = 0.5
+
+            [initial]
+            type = "radiative"
+
+            [time]
+            scheme = "ssp-rk3"
+            max_dt = 0.01
+
+            [stop]
+            energy_tolerance = 0.1
+            max_steps = 500
+
+            [output]
+            path = "out/final.csv"
+            every = 10
+
+            [map]
+            colormap = "diverging"
+            edges = true
+        "#;
+        let config = RunConfig::from_toml(toml).unwrap();
+        assert_eq!(config.mesh.mesh_type(), meshgen::MeshType::LatLon(9, 36));
+        assert_eq!(config.physics, PhysicsConfig { eps1: 1.0, eps2: 0.5, courant_number: 0.2 });
+        assert_eq!(config.scheme(), integrate::TimeScheme::SspRk3);
+        assert_eq!(config.forcing.insolation, cli::InsolationKind::Exact);
+        let sim = config.simulation().unwrap();
+        for cell in sim.network.cells.iter() {
+            let center = cell.center();
+            let albedo = if center.theta < 25f64.to_radians() { 0.6 } else { 0.0 };
+            let emissivity = if center.phi.cos() < -(10f64.to_radians().cos()) { 0.5 } else { 1.0 };
+            assert_eq!(cell.surface, surface::Surface { albedo, emissivity, heat_capacity: 2.0, ice: None });
+        }
+        let forcing::Forcing::Fixed(light) = config.forcing.forcing().unwrap() else { panic!("expected a fixed substellar point") };
+        assert!((light.substellar.theta - 60f64.to_radians()).abs() < 1e-12);
+        let control = config.timestep_control().unwrap();
+        assert_eq!((control.courant_number, control.fixed_dt, control.max_dt), (0.2, None, Some(0.01)));
+        assert_eq!(control.length_scale, pgen::LengthScale::MinCellSpacing);
+        assert_eq!(config.stop.max_steps, Some(500));
+        assert_eq!(config.stop.max_time, None);
+        assert_eq!(config.output.snapshot_path(20), Some(PathBuf::from("out/final_000020.csv")));
+        assert_eq!(config.log_level().unwrap(), log::LevelFilter::Info);
+        assert_eq!(config.map.options(&coords::Coordinate { phi: 0.0, theta: 1.0 }), render::MapOptions { colormap: render::Colormap::Diverging, edges: true, ..Default::default() });
+
+        let json = r#"{"mesh": {"type": "goldberg", "level": 2}, "initial": {"value": 0.3}, "log_level": "warn"}"#;
+        let config = RunConfig::from_json(json).unwrap();
+        assert_eq!(config.mesh.mesh_type(), meshgen::MeshType::Goldberg(2));
+        assert_eq!(config.timestep_control().unwrap().length_scale, pgen::LengthScale::MeanCellSize);
+        assert!(matches!(config.initial.initial_condition(), pgen::InitialCondition::Constant(v) if v == 0.3));
+        assert_eq!(config.log_level().unwrap(), log::LevelFilter::Warn);
+        assert_eq!(RunConfig::from_toml(&toml::to_string(&config).unwrap()).unwrap(), config);
+
+        assert!(RunConfig::from_toml("[physics]\neps3 = 1.0").is_err());
+        let orbit = RunConfig::from_toml("[forcing.orbit]\nperiod = 10.0\nobliquity = 90.0").unwrap().forcing.forcing().unwrap();
+        assert!(matches!(orbit, forcing::Forcing::Orbit(o) if o.period == 10.0 && (o.obliquity - FRAC_PI_2).abs() < 1e-15));
+        assert!(RunConfig::from_toml("[forcing.orbit]\neccentricity = 0.5").is_err());
+        let sweep = RunConfig::from_toml("[sweep]\nmin = 1.0\nmax = 2.0\nsteps = 3\n[surface.ice]\nalbedo = 0.6\nfreeze = 0.7\nwidth = 0.0").unwrap();
+        assert_eq!(sweep.sweep.unwrap().scales(), vec![1.0, 1.5, 2.0]);
+        assert_eq!(sweep.surface.ice, Some(surface::IceAlbedo { albedo: 0.6, freeze: 0.7, width: 0.0 }));
+    }
+}
@@ -3,86 +3,108 @@ use std::f64::consts::PI;
 use std::iter::zip;
 use core::fmt::Display;
 
-use log::{info,warn};
+use log::warn;
 
+use super::error::Error;
 use super::geometry::GreatCircle;
 
+/// How far a vector may be from unit length and still be taken as a point on the sphere
+static OFF_SPHERE_TOLERANCE: f64 = 1e-3;
+
 /// A coordinate in a spherical coordinate system
 #[derive(PartialEq,Clone,Copy,Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(try_from = "RawCoordinate"))]
 pub struct Coordinate{
     pub phi:f64,
     pub theta:f64
 }
 
+/// Unchecked form of `Coordinate` that deserialization goes through
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct RawCoordinate{
+    phi:f64,
+    theta:f64
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<RawCoordinate> for Coordinate{
+    type Error = Error;
+    fn try_from(raw:RawCoordinate)->Result<Coordinate,Error>{
+        Coordinate::new(raw.phi,raw.theta)
+    }
+}
+
 impl Coordinate{
     /// Create a new coordinate
     /// 
     /// Arguments must not be NaN and satisy
     /// $\theta \in (0,\pi)$
-    pub fn new(phi:f64,theta:f64)->Result<Coordinate,&'static str>{
-        if phi.is_nan() { Err("phi is nan") }
-        else if theta.is_nan() { Err("theta is nan") }
-        else if theta > PI { Err("theta must be less than PI") }
-        else if theta < 0.0 { Err("theta must be greater than 0.0") }
-        else { Ok(Coordinate{phi:phi,theta:theta}) }
+    pub fn new(phi:f64,theta:f64)->Result<Coordinate,Error>{
+        if phi.is_nan() || theta.is_nan() { Err(Error::NanCoordinate{context: "Coordinate::new"}) }
+        else if !(0.0..=PI).contains(&theta) { Err(Error::ThetaOutOfRange{theta}) }
+        else { Ok(Coordinate{phi,theta}) }
     }
     /// Convert a cartesian coordinate to a spherical coordinate.
     ///
-    /// Returns `Err` if any of the input arguments are `NaN`.
+    /// Returns `Err` if any of the input arguments are `NaN`
+    /// or the vector is not close to unit length.
     ///
-    pub fn from_cart(x:f64,y:f64,z:f64)->Result<Coordinate,&'static str>{
-        if x.is_nan() { Err("x is nan in from_cart") }
-        else if y.is_nan() { Err("y is nan in from_cart") }
-        else if z.is_nan() { Err("z is nan in from_cart") }
-        else {
-            let mag = (x*x + y*y + z*z).sqrt();
-            if (1.0 - mag).abs() > 1e-3 { 
-                warn!("Creating coordinate from vector not on unit sphere")
-             }
-            Ok(Coordinate::new((x/mag).atan2(y/mag),(z/mag).acos()).unwrap()) 
+    pub fn from_cart(x:f64,y:f64,z:f64)->Result<Coordinate,Error>{
+        if x.is_nan() || y.is_nan() || z.is_nan() { return Err(Error::NanCoordinate{context: "Coordinate::from_cart"}) }
+        let mag = (x*x + y*y + z*z).sqrt();
+        if (1.0 - mag).abs() > OFF_SPHERE_TOLERANCE {
+            return Err(Error::OffSphere{magnitude: mag})
         }
+        Coordinate::new((y/mag).atan2(x/mag),(z/mag).clamp(-1.0,1.0).acos())
     }
     /// Converts the spherical coordinate to a Cartesian coordinate.
     ///
-    /// # Returns
-    ///
-    /// Returns a `Result` containing a tuple of `(x, y, z)` coordinates if successful,
-    /// or a `String` error message if any of the Cartesian components is `NaN`.
-    ///
     /// # Errors
     ///
     /// An error is returned if any of the computed Cartesian components (x, y, or z) is `NaN`.
-    pub fn cart(self:&Coordinate)-> Result<(f64,f64,f64),String> {
-        let x = self.phi.cos()*self.theta.sin();
-        let y = self.phi.sin()*self.theta.sin();
-        let z = self.theta.cos();
-        if x.is_nan() { Err(String::from("x is nan in cart ") + self.to_string().as_str()) }
-        else if y.is_nan() { Err(String::from("x is nan in cart ") + self.to_string().as_str()) }
-        else if z.is_nan() { Err(String::from("x is nan in cart ") + self.to_string().as_str()) }
+    pub fn cart(self:&Coordinate)-> Result<(f64,f64,f64),Error> {
+        let (x,y,z) = self.xyz();
+        if x.is_nan() || y.is_nan() || z.is_nan() { Err(Error::NanCoordinate{context: "Coordinate::cart"}) }
         else { Ok((x,y,z)) }
     }
+    /// Cartesian components, `NaN` where `cart` would fail
+    fn xyz(self:&Coordinate)->(f64,f64,f64) {
+        (self.phi.cos()*self.theta.sin(), self.phi.sin()*self.theta.sin(), self.theta.cos())
+    }
 
-    pub fn dot(self:&Coordinate,other:&Coordinate)->Result<f64,&'static str> {
-        let res = self.theta.sin() * other.theta.sin() * (self.phi - other.phi).cos() + self.theta.cos()*other.theta.cos();
-        if res.is_nan() { Err("NaN in dot product")}
+    pub fn dot(self:&Coordinate,other:&Coordinate)->Result<f64,Error> {
+        let res = self.cos_angle(other);
+        if res.is_nan() { Err(Error::NanCoordinate{context: "Coordinate::dot"}) }
         else { Ok(res) }
     }
-    pub fn cross_normalized(self:&Coordinate,other:&Coordinate)->Result<Coordinate,&'static str> {
-        let (x1,y1,z1) = self.cart().unwrap();
-        let (x2,y2,z2) = other.cart().unwrap();
+    /// `dot` without the check, `NaN` where `dot` would fail
+    fn cos_angle(self:&Coordinate,other:&Coordinate)->f64 {
+        self.theta.sin() * other.theta.sin() * (self.phi - other.phi).cos() + self.theta.cos()*other.theta.cos()
+    }
+    /// Unit normal to the plane through the origin, `self` and `other`.
+    ///
+    /// Fails with `NanCoordinate` if the two points coincide or are antipodal.
+    pub fn cross_normalized(self:&Coordinate,other:&Coordinate)->Result<Coordinate,Error> {
+        let (x1,y1,z1) = self.cart()?;
+        let (x2,y2,z2) = other.cart()?;
         let x = y1*z2 - y2*z1;
-        let y = x1*z2 - x2*z1;
+        let y = z1*x2 - z2*x1;
         let z = x1*y2 - x2*y1;
         let mag = (x*x + y*y + z*z).sqrt();
 
         Coordinate::from_cart(x/mag,y/mag,z/mag)
     }
 
+    /// Magnitude of the cross product of the two points as unit vectors.
+    ///
+    /// This and the other measures returning a bare `f64` give `NaN` for a
+    /// coordinate with an infinite `phi` rather than failing.
     pub fn cross_mag(self:&Coordinate,other:&Coordinate)->f64 {
-        let (x1,y1,z1) = self.cart().unwrap();
-        let (x2,y2,z2) = other.cart().unwrap();
+        let (x1,y1,z1) = self.xyz();
+        let (x2,y2,z2) = other.xyz();
         let x = y1*z2 - y2*z1;
-        let y = x1*z2 - x2*z1;
+        let y = z1*x2 - z2*x1;
         let z = x1*y2 - x2*y1;
         (x*x + y*y + z*z).sqrt()
     }
@@ -91,25 +113,23 @@ impl Coordinate{
         self.cross_mag(other)
     }
     pub fn angle_between(self:&Coordinate,other:&Coordinate)->f64 {
-        if self.dot(other).unwrap() < 0.0 {
-            let (x1,y1,z1) = self.cart().unwrap();
-            let (x2,y2,z2) = other.cart().unwrap();
+        let (x1,y1,z1) = self.xyz();
+        let (x2,y2,z2) = other.xyz();
+        if self.cos_angle(other) < 0.0 {
             let dx = -x1 - x2;
             let dy = -y1 - y2;
             let dz = -z1 - z2;
             let half_mag = (dx*dx + dy*dy + dz*dz).sqrt()/2.0;
             
-            return PI - 2.0 * half_mag.asin()
+            PI - 2.0 * half_mag.asin()
         }
         else {
-            let (x1,y1,z1) = self.cart().unwrap();
-            let (x2,y2,z2) = other.cart().unwrap();
             let dx = x1 - x2;
             let dy = y1 - y2;
             let dz = z1 - z2;
             let half_mag = (dx*dx + dy*dy + dz*dz).sqrt()/2.0;
 
-            return 2.0 * half_mag.asin()
+            2.0 * half_mag.asin()
         }
     }
 }
@@ -121,30 +141,30 @@ impl Display for Coordinate {
 }
 
 pub fn spherical_angle(
-    A: &Coordinate,
-    B: &Coordinate,
-    C: &Coordinate
+    a: &Coordinate,
+    b: &Coordinate,
+    c: &Coordinate
 ) -> f64 {
     // Measure the angle of AB and BC
     
     // b is the distance AC
-    let cos_b = A.dot(C).unwrap();
+    let cos_b = a.cos_angle(c);
 
     // c is the distance AB
-    let cos_c = A.dot(B).unwrap();
-    let sin_c = A.sin_dist(B);
+    let cos_c = a.cos_angle(b);
+    let sin_c = a.sin_dist(b);
 
     // a is the distance BC
-    let cos_a = B.dot(C).unwrap();
-    let sin_a = B.sin_dist(C);
+    let cos_a = b.cos_angle(c);
+    let sin_a = b.sin_dist(c);
 
-    let cos_B = (cos_b - cos_c * cos_a)/(sin_c*sin_a);
-    cos_B.acos()
+    let cos_angle_b = (cos_b - cos_c * cos_a)/(sin_c*sin_a);
+    cos_angle_b.acos()
 }
 
-pub fn midpoint(a: &Coordinate, b: &Coordinate) -> Result<Coordinate, &'static str> {
-    let (x1,y1,z1) = a.cart().unwrap();
-    let (x2,y2,z2) = b.cart().unwrap();
+pub fn midpoint(a: &Coordinate, b: &Coordinate) -> Result<Coordinate, Error> {
+    let (x1,y1,z1) = a.cart()?;
+    let (x2,y2,z2) = b.cart()?;
     let x = (x1 + x2)/2.0;
     let y = (y1 + y2)/2.0;
     let z = (z1 + z2)/2.0;
@@ -156,11 +176,12 @@ pub fn length(a: &Coordinate, b: &Coordinate) -> f64 {
     a.angle_between(b)
 }
 pub fn phihat_dot_nhat(a: &Coordinate, nhat: &Coordinate) -> f64 {
-    let (nx, ny, _) = nhat.cart().unwrap();
+    let (nx, ny, _) = nhat.xyz();
     let phi = a.phi;
     ny * phi.cos() - nx * phi.sin()
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Edge{
     pub a:Coordinate,
     pub b:Coordinate
@@ -168,24 +189,24 @@ pub struct Edge{
 
 impl Edge{
     pub fn new(a:Coordinate,b:Coordinate)->Edge{
-        Edge{a:a,b:b}
+        Edge{a,b}
     }
     pub fn len(self:&Edge)->f64{
         length(&self.a,&self.b)
     }
-    pub fn midpoint(self:&Edge)->Result<Coordinate,&'static str>{
+    pub fn midpoint(self:&Edge)->Result<Coordinate,Error>{
         midpoint(&self.a,&self.b)
     }
-    pub fn get_great_circle(self:&Edge)->GreatCircle{
-        GreatCircle::from_coords(self.a.clone(),self.b.clone())
+    pub fn get_great_circle(self:&Edge)->Result<GreatCircle,Error>{
+        GreatCircle::from_coords(self.a,self.b)
     }
     /// Compute $\hat{\phi} \cdot \hat{n}$ using Simpson's rule
-    pub fn phihat_dot_nhat(self:&Edge)->f64{
-        let nhat = self.get_great_circle().nhat();
+    pub fn phihat_dot_nhat(self:&Edge)->Result<f64,Error>{
+        let nhat = self.get_great_circle()?.nhat()?;
         let f_a = phihat_dot_nhat(&self.a,&nhat);
         let f_b = phihat_dot_nhat(&self.b,&nhat);
-        let f_mid = phihat_dot_nhat(&self.midpoint().unwrap(),&nhat);
-        self.len()/6.0 * (f_a + f_b + 4.0 * f_mid)
+        let f_mid = phihat_dot_nhat(&self.midpoint()?,&nhat);
+        Ok(self.len()/6.0 * (f_a + f_b + 4.0 * f_mid))
     }
 }
 impl PartialEq for Edge{
@@ -200,31 +221,32 @@ pub struct PolyLine{
 
 impl PolyLine{
     pub fn new(nodes:Vec<Coordinate>)->PolyLine{
-        PolyLine{nodes:nodes}
+        PolyLine{nodes}
     }
     pub fn to_edges(self:&PolyLine)->Vec<Edge>{
         let mut edges:Vec<Edge> = Vec::new();
         for i in 0..self.nodes.len()-1{
-            edges.push(Edge::new(self.nodes[i].clone(),self.nodes[i+1].clone()));
+            edges.push(Edge::new(self.nodes[i],self.nodes[i+1]));
         }
         edges
     }
 }
 #[derive(Clone,Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Polygon{
     pub nodes:Vec<Coordinate>
 }
 
 impl Polygon{
     pub fn new(nodes:Vec<Coordinate>)->Polygon{
-        Polygon{nodes:nodes}
+        Polygon{nodes}
     }
     pub fn to_edges(self:&Polygon)->Vec<Edge>{
         let mut edges:Vec<Edge> = Vec::new();
         for i in 0..self.nodes.len()-1{
-            edges.push(Edge::new(self.nodes[i].clone(),self.nodes[i+1].clone()));
+            edges.push(Edge::new(self.nodes[i],self.nodes[i+1]));
         }
-        edges.push(Edge::new(self.nodes[self.nodes.len()-1].clone(),self.nodes[0].clone()));
+        edges.push(Edge::new(self.nodes[self.nodes.len()-1],self.nodes[0]));
         edges
     }
     pub fn interior_angles(self:&Polygon)->Vec<f64>{
@@ -238,31 +260,32 @@ impl Polygon{
     }
 
     pub fn area(self:&Polygon)->f64{
-        let mut area:f64 = 0.0;
         let angles = self.interior_angles();
         let n = angles.len();
-        for i in 0..n{
-            area += angles[i];
-        }
-        area - (n as f64 - 2.0) * PI 
+        angles.iter().sum::<f64>() - (n as f64 - 2.0) * PI
     }
-    pub fn center(self:&Polygon)->Coordinate{
+    /// Centroid of the polygon on the sphere.
+    ///
+    /// Fails with `NanCoordinate` if two consecutive nodes coincide or are
+    /// antipodal, since the edge between them is then undefined.
+    pub fn center(self:&Polygon)->Result<Coordinate,Error>{
         let mut x = 0.0;
         let mut y = 0.0;
         let mut z = 0.0;
         for edge in self.to_edges(){
             let a = edge.a;
             let b = edge.b;
-            let v = a.cross_normalized(&b).unwrap();
-            let v_mag = a.cross_mag(&b);
+            let v = a.cross_normalized(&b)?;
             let angle = a.angle_between(&b);
-            let (_x, _y, _z) = v.cart().unwrap();
+            let (_x, _y, _z) = v.cart()?;
             x += _x * angle/2.0;
             y += _y * angle/2.0;
             z += _z * angle/2.0;
         }
-        let mag = (x*x + y*y + z*z).sqrt();
-        Coordinate::from_cart(x/mag,y/mag,z/mag).unwrap()
+        // A clockwise polygon gives the antipode of the centroid
+        let (x0,y0,z0) = self.nodes[0].cart()?;
+        let mag = (x*x + y*y + z*z).sqrt() * (x*x0 + y*y0 + z*z0).signum();
+        Coordinate::from_cart(x/mag,y/mag,z/mag)
     }
 }
 
@@ -316,45 +339,107 @@ impl PartialEq for Polygon{
     }
 }
 
-pub fn subdivide_polygon(polygon: Polygon) -> Vec<Polygon> {
+pub fn subdivide_polygon(polygon: Polygon) -> Result<Vec<Polygon>, Error> {
     let original_area = polygon.area();
     let edges = polygon.to_edges();
     let n_new_polygons = edges.len();
     let expected_area = original_area / n_new_polygons as f64;
-    let centroid = polygon.center();
+    let centroid = polygon.center()?;
     let mut polygons: Vec<Polygon> = Vec::new();
     for edge in edges.iter() {
         let a = edge.a;
         let b = edge.b;
-        let new_polygon = Polygon::new(vec![centroid.clone(), a.clone(), b.clone()]);
+        let new_polygon = Polygon::new(vec![centroid, a, b]);
         let new_area = new_polygon.area();
         if 1.0 - new_area / expected_area > 0.01 {
             warn!("Expected area of {} but got {}", expected_area, new_area);
         }
         polygons.push(new_polygon);
     }
-    polygons
+    Ok(polygons)
+}
+
+/// Split a triangle into four by joining the midpoints of its edges.
+///
+/// The winding order of the input is preserved in each child.
+pub fn subdivide_triangle(polygon: Polygon) -> Result<Vec<Polygon>, Error> {
+    if polygon.nodes.len() != 3 {
+        return Err(Error::InvalidArgument("Only triangles can be split at their edge midpoints"));
+    }
+    let a = polygon.nodes[0];
+    let b = polygon.nodes[1];
+    let c = polygon.nodes[2];
+    let ab = midpoint(&a, &b)?;
+    let bc = midpoint(&b, &c)?;
+    let ca = midpoint(&c, &a)?;
+    Ok(vec![
+        Polygon::new(vec![a, ab, ca]),
+        Polygon::new(vec![ab, b, bc]),
+        Polygon::new(vec![ca, bc, c]),
+        Polygon::new(vec![ab, bc, ca]),
+    ])
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     #[test]
+    fn test_cartesian_round_trip() {
+        for (phi, theta) in [(0.3, 1.2), (2.0, 0.4), (-2.5, 2.9), (-0.7, 1.6)] {
+            let c = Coordinate::new(phi, theta).unwrap();
+            let (x, y, z) = c.cart().unwrap();
+            let back = Coordinate::from_cart(x, y, z).unwrap();
+            assert!((back.phi - phi).abs() < 1e-12 && (back.theta - theta).abs() < 1e-12, "{} came back as {}", c, back);
+        }
+        // x cross y is z, and y cross x is -z
+        let x = Coordinate::from_cart(1.0, 0.0, 0.0).unwrap();
+        let y = Coordinate::from_cart(0.0, 1.0, 0.0).unwrap();
+        assert_eq!(x.cross_normalized(&y).unwrap().cart().unwrap(), (0.0, 0.0, 1.0));
+        assert!((y.cross_normalized(&x).unwrap().theta - PI).abs() < 1e-12);
+        let z = Coordinate::from_cart(0.0, 0.0, 1.0).unwrap();
+        assert!(z.cross_normalized(&x).unwrap().angle_between(&y) < 1e-12);
+        // Either orientation of a polygon has the same center
+        let ccw = Polygon::new(vec![z, x, y]);
+        let cw = Polygon::new(vec![z, y, x]);
+        let expected = Coordinate::from_cart(1.0 / 3f64.sqrt(), 1.0 / 3f64.sqrt(), 1.0 / 3f64.sqrt()).unwrap();
+        assert!(ccw.center().unwrap().angle_between(&expected) < 1e-12);
+        assert!(cw.center().unwrap().angle_between(&expected) < 1e-12);
+    }
+    #[test]
     fn test_subdivide_polygon() {
         let n_pole = Coordinate::from_cart(0.0, 0.0, 1.0).unwrap();
         let eq_1 = Coordinate::from_cart(1.0, 0.0, 0.0).unwrap();
         let eq_2 = Coordinate::from_cart(0.0, 1.0, 0.0).unwrap();
         let poly = Polygon::new(vec![n_pole, eq_1, eq_2]);
-        let sub = subdivide_polygon(poly.clone());
+        let sub = subdivide_polygon(poly.clone()).unwrap();
         assert_eq!(sub.len(), 3);
         assert_eq!(sub[0].area(), sub[1].area());
         assert_eq!(sub[0].area(), sub[2].area());
 
-        let center = poly.center();
+        let center = poly.center().unwrap();
         assert_eq!(sub[0], Polygon::new(vec![center, n_pole, eq_1]));
         assert_eq!(sub[1], Polygon::new(vec![center, eq_1, eq_2]));
         assert_eq!(sub[2], Polygon::new(vec![center, eq_2, n_pole]));
 
         assert!(sub[0].ne(&sub[1]));
     }
+    #[test]
+    fn test_subdivide_triangle() {
+        let n_pole = Coordinate::from_cart(0.0, 0.0, 1.0).unwrap();
+        let eq_1 = Coordinate::from_cart(1.0, 0.0, 0.0).unwrap();
+        let eq_2 = Coordinate::from_cart(0.0, 1.0, 0.0).unwrap();
+        let poly = Polygon::new(vec![n_pole, eq_1, eq_2]);
+        let sub = subdivide_triangle(poly.clone()).unwrap();
+        assert_eq!(sub.len(), 4);
+        let total: f64 = sub.iter().map(|s| s.area()).sum();
+        assert!((total - poly.area()).abs() < 1e-12);
+        // The corner triangles are congruent and the middle one is larger
+        assert!((sub[0].area() - sub[1].area()).abs() < 1e-12);
+        assert!((sub[0].area() - sub[2].area()).abs() < 1e-12);
+        assert!(sub[3].area() > sub[0].area());
+        assert!(sub[3].center().unwrap().angle_between(&poly.center().unwrap()) < 1e-12);
+
+        let square = Polygon::new(vec![n_pole, eq_1, eq_2, eq_1]);
+        assert!(subdivide_triangle(square).is_err());
+    }
 }
\ No newline at end of file
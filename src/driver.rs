@@ -0,0 +1,251 @@
+//! Run a simulation until a stopping criterion is met
+//!
+//!
+
+use log::{info,warn};
+
+use super::{forcing, grid, integrate, pgen};
+use super::error::Error;
+
+/// When to stop a run. Criteria left as `None` are not checked;
+/// the run stops on whichever of the others is met first.
+#[derive(Clone,Debug,Default,PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(default, deny_unknown_fields))]
+pub struct StoppingCriteria {
+    /// Stop once the magnitude of the energy imbalance, in percent, is below this.
+    /// Without starlight the absolute imbalance is used instead.
+    pub energy_tolerance: Option<f64>,
+    /// Stop once no cell value changes by more than this in one step
+    pub max_change: Option<f64>,
+    /// Stop once the model time reaches this
+    pub max_time: Option<f64>,
+    /// Stop after this many steps
+    pub max_steps: Option<usize>
+}
+
+impl StoppingCriteria {
+    pub fn is_empty(self:&StoppingCriteria) -> bool {
+        self.energy_tolerance.is_none() && self.max_change.is_none() && self.max_time.is_none() && self.max_steps.is_none()
+    }
+}
+
+/// How the driver picks each timestep
+#[derive(Clone,Debug,PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(default, deny_unknown_fields))]
+pub struct TimestepControl {
+    /// Safety factor on the explicit stability limits
+    pub courant_number: f64,
+    /// Use this timestep instead of the stability limit
+    pub fixed_dt: Option<f64>,
+    pub max_dt: Option<f64>,
+    /// Give up once a failing step has been shrunk below this
+    pub min_dt: Option<f64>,
+    /// Factor a step is shrunk by before retrying when it produces a negative temperature
+    pub shrink_factor: f64,
+    /// Cell size the stability limits are measured against
+    pub length_scale: pgen::LengthScale
+}
+
+impl Default for TimestepControl {
+    fn default() -> TimestepControl {
+        TimestepControl{courant_number: pgen::COURANT_NUMBER, fixed_dt: None, max_dt: None, min_dt: None, shrink_factor: 0.5, length_scale: pgen::LengthScale::MeanCellSize}
+    }
+}
+
+/// Smallest step tried when no `min_dt` is given
+static SMALLEST_DT: f64 = 1e-12;
+
+impl TimestepControl {
+    /// A step is shrunk until it succeeds, so the factor must be in (0, 1) for that to end
+    pub fn check(self:&TimestepControl) -> Result<(),Error> {
+        if !(self.shrink_factor > 0.0 && self.shrink_factor < 1.0) {
+            return Err(Error::InvalidArgument("shrink factor must be between 0 and 1"));
+        }
+        Ok(())
+    }
+    /// Timestep to attempt first for the current state of `network`,
+    /// and the stability limit it was taken from unless it is fixed
+    pub fn choose(self:&TimestepControl, network: &grid::GridNetwork, eps1: f64, eps2: f64) -> (f64, Option<pgen::CFL_Limiter>) {
+        let (dt, limiter) = match self.fixed_dt {
+            Some(dt) => (dt, None),
+            None => {
+                let limit = pgen::cfl_timestep(network,self.courant_number,self.length_scale,eps1,eps2);
+                match limit {
+                    pgen::CFL_Limiter::AdvectionLimited(adv) => info!("Advection limited timestep: {}",adv),
+                    pgen::CFL_Limiter::DiffusionLimited(diff) => info!("Diffusion limited timestep: {}",diff),
+                    pgen::CFL_Limiter::SourceLimited(source) => info!("Source limited timestep: {}",source),
+                    pgen::CFL_Limiter::NoLimit(no_limit) => info!("No limit timestep: {}",no_limit)
+                }
+                (limit.dt(), Some(limit))
+            }
+        };
+        let dt = self.max_dt.map_or(dt, |max_dt| dt.min(max_dt));
+        (self.min_dt.map_or(dt, |min_dt| dt.max(min_dt)), limiter)
+    }
+}
+
+/// Which criterion ended a run
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum StopReason {
+    EnergyBalanced(f64),
+    ChangeBelowTolerance(f64),
+    MaxTime(f64),
+    MaxSteps(usize)
+}
+
+pub struct RunSummary {
+    pub reason: StopReason,
+    pub steps: usize,
+    pub time: f64
+}
+
+pub struct Simulation {
+    pub network: grid::GridNetwork,
+    pub eps1: f64,
+    pub eps2: f64,
+    pub scheme: integrate::TimeScheme,
+    pub timestep: TimestepControl,
+    /// Starlight, evaluated at the start of every step and held over it
+    pub forcing: forcing::Forcing,
+    /// How the starlight is integrated over each cell, whatever the rule of the forcing
+    pub insolation: forcing::InsolationRule,
+    /// Model time
+    pub time: f64,
+    /// Steps taken so far
+    pub steps: usize,
+    /// Size of the last step taken
+    pub last_dt: Option<f64>,
+    /// Stability limit the last step was chosen from; `None` for a fixed timestep
+    pub limiter: Option<pgen::CFL_Limiter>
+}
+
+impl Simulation {
+    pub fn new(network: grid::GridNetwork, eps1: f64, eps2: f64, scheme: integrate::TimeScheme) -> Simulation {
+        Simulation{network, eps1, eps2, scheme, timestep: TimestepControl::default(), forcing: forcing::Forcing::default(), insolation: forcing::InsolationRule::default(), time: 0.0, steps: 0, last_dt: None, limiter: None}
+    }
+    /// Illumination at the current model time
+    pub fn illumination(self:&Simulation) -> Result<forcing::Illumination,Error> {
+        Ok(forcing::Illumination{rule: self.insolation, ..self.forcing.illumination(self.time)?})
+    }
+    /// Take one step, stopping short at model time `until` if given.
+    ///
+    /// A step that would make a cell value negative is shrunk and retried.
+    /// Returns the largest change in any cell value.
+    pub fn step(self:&mut Simulation, until: Option<f64>) -> Result<f64,Error> {
+        self.timestep.check()?;
+        let (mut dt, limiter) = self.timestep.choose(&self.network,self.eps1,self.eps2);
+        let min_dt = self.timestep.min_dt.unwrap_or(SMALLEST_DT);
+        let light = self.illumination()?;
+        loop {
+            let (step_dt, end) = match until {
+                Some(until) if until - self.time <= dt => (until - self.time, until),
+                _ => (dt, self.time + dt)
+            };
+            match integrate::step(&self.network,self.eps1,self.eps2,&light,step_dt,self.scheme) {
+                Ok(next) => {
+                    let change = self.network.cells.iter().zip(next.cells.iter()).map(|(a, b)| (a.value - b.value).abs()).fold(0.0,f64::max);
+                    self.network = next;
+                    self.time = end;
+                    self.steps += 1;
+                    self.last_dt = Some(step_dt);
+                    self.limiter = limiter;
+                    return Ok(change);
+                },
+                Err(Error::NegativeTemperature{cell, value}) => {
+                    dt = step_dt * self.timestep.shrink_factor;
+                    if dt < min_dt {
+                        return Err(Error::StepTooSmall{dt, min_dt});
+                    }
+                    warn!("Step {} with dt = {} gave a negative temperature {} in cell {:?}; retrying with dt = {}",self.steps,step_dt,value,cell,dt);
+                },
+                Err(e) => return Err(e)
+            }
+        }
+    }
+    pub fn run(self:&mut Simulation, criteria: &StoppingCriteria) -> Result<RunSummary,Error> {
+        self.run_with(criteria,|_| Ok(()))
+    }
+    /// Run until one of `criteria` is met, calling `on_step` after every step
+    pub fn run_with<F>(self:&mut Simulation, criteria: &StoppingCriteria, mut on_step: F) -> Result<RunSummary,Error>
+    where F: FnMut(&Simulation) -> Result<(),Error> {
+        if criteria.is_empty() {
+            return Err(Error::InvalidArgument("No stopping criterion given"));
+        }
+        let start_steps = self.steps;
+        let reason = loop {
+            if let Some(max_steps) = criteria.max_steps {
+                if self.steps - start_steps >= max_steps { break StopReason::MaxSteps(max_steps); }
+            }
+            if let Some(max_time) = criteria.max_time {
+                if self.time >= max_time { break StopReason::MaxTime(self.time); }
+            }
+            let change = self.step(criteria.max_time)?;
+            on_step(self)?;
+            if let Some(tolerance) = criteria.energy_tolerance {
+                let light = self.illumination()?;
+                let (energy_in, energy_out) = pgen::energy_totals(&self.network,&light);
+                let imbalance = if energy_in > 0.0 { pgen::check_energy_balance(&self.network,&light).percent() } else { energy_in - energy_out };
+                if imbalance.abs() < tolerance { break StopReason::EnergyBalanced(imbalance); }
+            }
+            if let Some(max_change) = criteria.max_change {
+                if change < max_change { break StopReason::ChangeBelowTolerance(change); }
+            }
+        };
+        let steps = self.steps - start_steps;
+        info!("Stopped after {} steps at time {}: {:?}",steps,self.time,reason);
+        Ok(RunSummary{reason, steps, time: self.time})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meshgen::MeshType;
+
+    #[test]
+    fn test_stopping_criteria() {
+        let network = pgen::init_mesh(MeshType::Geodesic(1), pgen::InitialCondition::Radiative).unwrap();
+        let mut sim = Simulation::new(network, 1.0, 1.0, integrate::TimeScheme::ForwardEuler);
+        let summary = sim.run(&StoppingCriteria{max_steps: Some(5), ..Default::default()}).unwrap();
+        assert_eq!(summary.reason, StopReason::MaxSteps(5));
+        assert_eq!(summary.steps, 5);
+
+        // The last step is shortened to land on the end time
+        let end = sim.time + 0.05;
+        let summary = sim.run(&StoppingCriteria{max_time: Some(end), max_steps: Some(10000), ..Default::default()}).unwrap();
+        assert_eq!(summary.reason, StopReason::MaxTime(end));
+        assert_eq!(sim.steps, 5 + summary.steps);
+
+        let summary = sim.run(&StoppingCriteria{energy_tolerance: Some(0.5), max_change: Some(1e-12), ..Default::default()}).unwrap();
+        assert!(matches!(summary.reason, StopReason::EnergyBalanced(pct) if pct.abs() < 0.5));
+
+        assert!(sim.run(&StoppingCriteria::default()).is_err());
+
+        // With no starlight there is no percentage to take, so the absolute imbalance is used
+        let network = pgen::init_mesh(MeshType::Geodesic(1), pgen::InitialCondition::Constant(0.1)).unwrap();
+        let mut sim = Simulation::new(network, 1.0, 1.0, integrate::TimeScheme::ForwardEuler);
+        sim.forcing = forcing::Forcing::Fixed(forcing::Illumination{scale: 0.0, ..Default::default()});
+        let summary = sim.run(&StoppingCriteria{energy_tolerance: Some(0.01), max_steps: Some(100), ..Default::default()}).unwrap();
+        assert!(matches!(summary.reason, StopReason::EnergyBalanced(imbalance) if imbalance < 0.0 && imbalance > -0.01));
+    }
+
+    #[test]
+    fn test_retry_negative_temperature() {
+        let network = pgen::init_mesh(MeshType::Geodesic(1), pgen::InitialCondition::Radiative).unwrap();
+        let mut sim = Simulation::new(network, 1.0, 1.0, integrate::TimeScheme::ForwardEuler);
+        let limit = pgen::cfl_timestep(&sim.network, pgen::COURANT_NUMBER, pgen::LengthScale::MeanCellSize, 1.0, 1.0).dt();
+        sim.timestep.fixed_dt = Some(100.0 * limit);
+        sim.step(None).unwrap();
+        assert!(sim.time < 100.0 * limit);
+        assert!(sim.network.min_value() >= 0.0);
+
+        sim.timestep.min_dt = Some(50.0 * limit);
+        assert!(matches!(sim.step(None), Err(Error::StepTooSmall{..})));
+
+        // A factor that does not shrink the step would retry it forever
+        for factor in [1.0, 0.0, f64::NAN] {
+            sim.timestep.shrink_factor = factor;
+            assert!(matches!(sim.step(None), Err(Error::InvalidArgument(_))));
+        }
+    }
+}
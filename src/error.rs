@@ -0,0 +1,75 @@
+//! Errors shared by the whole crate
+//!
+//!
+
+use std::fmt::Display;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A coordinate or vector component is NaN
+    NanCoordinate { context: &'static str },
+    /// A colatitude outside `[0, π]`
+    ThetaOutOfRange { theta: f64 },
+    /// A Cartesian vector that should lie on the unit sphere does not
+    OffSphere { magnitude: f64 },
+    /// An edge of `cell` is shared by more than two cells
+    NonManifoldEdge { cell: usize, cells: Vec<usize> },
+    /// An edge of `cell` has no cell on its other side
+    BoundaryEdge { cell: usize },
+    /// An edge was used with a cell it does not belong to
+    EdgeNotInPolygon { cell: usize },
+    /// A cell value is, or would become, negative
+    NegativeTemperature { cell: Option<usize>, value: f64 },
+    /// A timestep `dt` exceeds the explicit stability limit `min_dt` it must stay within
+    CflViolation { dt: f64, min_dt: f64 },
+    /// A failing step had to shrink below the allowed minimum to keep the update valid
+    StepTooSmall { dt: f64, min_dt: f64 },
+    /// An iterative solver gave up
+    SolverFailure { solver: &'static str, reason: &'static str },
+    InvalidArgument(&'static str),
+    /// A mesh or data file could not be parsed
+    Parse { line: usize, reason: &'static str },
+    /// A checkpoint file is damaged or was not written by this program
+    InvalidCheckpoint(&'static str),
+    /// The run was stopped from outside, e.g. by Ctrl-C
+    Interrupted,
+    Io(std::io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::NanCoordinate { context } => write!(f, "NaN coordinate in {}", context),
+            Error::ThetaOutOfRange { theta } => write!(f, "theta = {} is outside [0, PI]", theta),
+            Error::OffSphere { magnitude } => write!(f, "vector of magnitude {} is not on the unit sphere", magnitude),
+            Error::NonManifoldEdge { cell, cells } => write!(f, "an edge of cell {} separates more than two cells: {:?}", cell, cells),
+            Error::BoundaryEdge { cell } => write!(f, "an edge of cell {} borders only one cell", cell),
+            Error::EdgeNotInPolygon { cell } => write!(f, "edge is not a side of cell {}", cell),
+            Error::NegativeTemperature { cell: Some(cell), value } => write!(f, "negative temperature {} in cell {}", value, cell),
+            Error::NegativeTemperature { cell: None, value } => write!(f, "negative temperature {}", value),
+            Error::CflViolation { dt, min_dt } => write!(f, "timestep {} exceeds the stability limit {}", dt, min_dt),
+            Error::StepTooSmall { dt, min_dt } => write!(f, "timestep {} needed for a valid update is below the minimum {}", dt, min_dt),
+            Error::SolverFailure { solver, reason } => write!(f, "{} failed: {}", solver, reason),
+            Error::InvalidArgument(reason) => write!(f, "{}", reason),
+            Error::Parse { line, reason } => write!(f, "line {}: {}", line, reason),
+            Error::InvalidCheckpoint(reason) => write!(f, "invalid checkpoint: {}", reason),
+            Error::Interrupted => write!(f, "interrupted"),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::Io(e)
+    }
+}
@@ -0,0 +1,273 @@
+//! Stellar forcing
+//!
+//! The incident flux on a cell depends on where the star is overhead, the
+//! substellar point, and on how bright it is. Both can be held fixed, or
+//! follow a planet on an eccentric orbit with a tilted spin axis.
+//!
+//! Orbiting planets are taken to rotate synchronously, once per orbit, so on
+//! a circular orbit without obliquity the substellar point stays at longitude
+//! 0 on the equator. Eccentricity makes it librate in longitude and
+//! obliquity moves it north and south with the seasons.
+//!
+//! The starlight falling on a cell is the integral of the cosine of the
+//! zenith angle over its day side. `InsolationRule::Exact` clips the cell to
+//! the day-side hemisphere and integrates exactly; `InsolationRule::Centroid`
+//! takes the value at the centroid times the area, which is cheaper but
+//! wrong near the terminator.
+
+use std::f64::consts::{FRAC_PI_2, PI};
+
+use super::coords;
+use super::error::Error;
+
+static KEPLER_TOLERANCE: f64 = 1e-14;
+static MAX_KEPLER_ITERATIONS: usize = 50;
+
+/// How the starlight is integrated over a cell
+#[derive(Clone,Copy,Debug,Default,PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum InsolationRule {
+    /// Flux at the centroid times the area
+    #[default]
+    Centroid,
+    /// Exact integral over the part of the cell on the day side
+    Exact
+}
+
+/// Where the star is overhead and how bright it is at one instant
+#[derive(Clone,Copy,Debug,PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Illumination {
+    pub substellar: coords::Coordinate,
+    /// Stellar flux relative to its mean, `(a/r)^2` on an orbit
+    pub scale: f64,
+    pub rule: InsolationRule
+}
+
+impl Default for Illumination {
+    /// Unit flux from overhead at longitude 0 on the equator
+    fn default() -> Illumination {
+        Illumination{substellar: coords::Coordinate{phi: 0.0, theta: FRAC_PI_2}, scale: 1.0, rule: InsolationRule::default()}
+    }
+}
+
+type Vector = [f64; 3];
+
+fn unit(c: &coords::Coordinate) -> Vector {
+    [c.theta.sin() * c.phi.cos(), c.theta.sin() * c.phi.sin(), c.theta.cos()]
+}
+
+fn dot(a: Vector, b: Vector) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+/// Integral of the outward normal over a spherical polygon with great-circle edges,
+/// `sum(theta_i * m_i) / 2` over edges of length `theta_i` and unit plane normal `m_i`.
+///
+/// It points out of the polygon for corners in counterclockwise order and
+/// into the sphere otherwise.
+fn vector_area(nodes: &[Vector]) -> Vector {
+    let mut total = [0.0; 3];
+    for (i, &a) in nodes.iter().enumerate() {
+        let b = nodes[(i + 1) % nodes.len()];
+        let m = [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]];
+        let sin = dot(m, m).sqrt();
+        // Repeated corners, like the poles of a lat-lon mesh, have no edge between them
+        if sin < 1e-15 { continue; }
+        let angle = sin.atan2(dot(a, b));
+        for k in 0..3 {
+            total[k] += 0.5 * angle * m[k] / sin;
+        }
+    }
+    total
+}
+
+impl Illumination {
+    /// Unit flux from overhead at `lat`, `lon` in radians
+    pub fn overhead(lat: f64, lon: f64) -> Result<Illumination,Error> {
+        Ok(Illumination{substellar: coords::Coordinate::new(lon, FRAC_PI_2 - lat)?, scale: 1.0, rule: InsolationRule::default()})
+    }
+    /// Starlight falling on `polygon`: the integral of `flux_at` over it, computed by `rule`.
+    ///
+    /// `NaN` for a polygon without a centroid under the centroid rule; the
+    /// polygon of a `grid::GridCell` always has one.
+    pub fn flux_over(self:&Illumination, polygon:&coords::Polygon) -> f64 {
+        match self.rule {
+            InsolationRule::Centroid => polygon.center().map_or(f64::NAN, |c| self.flux_at(&c)) * polygon.area(),
+            InsolationRule::Exact => {
+                let s = unit(&self.substellar);
+                let nodes: Vec<Vector> = polygon.nodes.iter().map(unit).collect();
+                let heights: Vec<f64> = nodes.iter().map(|&n| dot(n, s)).collect();
+                if heights.iter().all(|&h| h <= 0.0) { return 0.0; }
+                let outward = dot(vector_area(&nodes), nodes.iter().fold([0.0; 3], |c, n| [c[0] + n[0], c[1] + n[1], c[2] + n[2]])).signum();
+                // Clip to the day side. The terminator is a great circle, so the clipped polygon still has great-circle edges
+                let mut day = Vec::with_capacity(nodes.len() + 2);
+                for (i, (&a, &ha)) in nodes.iter().zip(heights.iter()).enumerate() {
+                    let (b, hb) = (nodes[(i + 1) % nodes.len()], heights[(i + 1) % nodes.len()]);
+                    if ha >= 0.0 { day.push(a); }
+                    if (ha >= 0.0) != (hb >= 0.0) {
+                        let p = [0, 1, 2].map(|k| (ha * b[k] - hb * a[k]) / (ha - hb));
+                        let mag = dot(p, p).sqrt();
+                        day.push(p.map(|x| x / mag));
+                    }
+                }
+                self.scale * (outward * dot(vector_area(&day), s)).max(0.0)
+            }
+        }
+    }
+    /// Incident flux per unit area at `point`: `scale * max(cos(zenith angle), 0)`
+    pub fn flux_at(self:&Illumination, point:&coords::Coordinate) -> f64 {
+        let s = &self.substellar;
+        let cos_zenith = point.theta.sin() * s.theta.sin() * (point.phi - s.phi).cos() + point.theta.cos() * s.theta.cos();
+        self.scale * cos_zenith.max(0.0)
+    }
+}
+
+/// A Keplerian orbit and the tilt of the planet's spin axis. Angles are in radians.
+#[derive(Clone,Copy,Debug,PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Orbit {
+    /// Model time of one orbit
+    pub period: f64,
+    pub eccentricity: f64,
+    /// Angle between the spin axis and the orbit normal
+    pub obliquity: f64,
+    /// Angle along the orbit from the northern spring equinox to periastron
+    pub periastron: f64,
+    /// True anomaly at time 0
+    pub true_anomaly: f64
+}
+
+impl Orbit {
+    fn check(self:&Orbit) -> Result<(),Error> {
+        if self.period.is_nan() || self.period <= 0.0 {
+            return Err(Error::InvalidArgument("The orbital period must be positive"));
+        }
+        if !(0.0..1.0).contains(&self.eccentricity) {
+            return Err(Error::InvalidArgument("The eccentricity must be in [0, 1)"));
+        }
+        Ok(())
+    }
+    /// Mean anomaly at time 0
+    fn initial_mean_anomaly(self:&Orbit) -> f64 {
+        let e = self.eccentricity;
+        let eccentric = 2.0 * ((1.0 - e).sqrt() * (self.true_anomaly / 2.0).sin()).atan2((1.0 + e).sqrt() * (self.true_anomaly / 2.0).cos());
+        eccentric - e * eccentric.sin()
+    }
+    /// True anomaly for mean anomaly `mean`, solving Kepler's equation by Newton iteration
+    fn true_anomaly_at(self:&Orbit, mean: f64) -> Result<f64,Error> {
+        let e = self.eccentricity;
+        let mean = (mean + PI).rem_euclid(2.0 * PI) - PI;
+        let mut eccentric = if e > 0.8 { PI.copysign(mean) } else { mean };
+        let mut converged = false;
+        for _ in 0..MAX_KEPLER_ITERATIONS {
+            let step = (eccentric - e * eccentric.sin() - mean) / (1.0 - e * eccentric.cos());
+            eccentric -= step;
+            if step.abs() < KEPLER_TOLERANCE {
+                converged = true;
+                break;
+            }
+        }
+        if !converged {
+            return Err(Error::SolverFailure{solver: "Kepler's equation", reason: "did not converge"});
+        }
+        Ok(2.0 * ((1.0 + e).sqrt() * (eccentric / 2.0).sin()).atan2((1.0 - e).sqrt() * (eccentric / 2.0).cos()))
+    }
+    /// Substellar point and stellar flux at model time `time`
+    pub fn illumination(self:&Orbit, time: f64) -> Result<Illumination,Error> {
+        self.check()?;
+        let e = self.eccentricity;
+        let mean = self.initial_mean_anomaly() + 2.0 * PI * time / self.period;
+        let nu = self.true_anomaly_at(mean)?;
+        // Direction to the star in a frame with x towards the spring equinox and z along the spin axis
+        let season = nu + self.periastron;
+        let star = (-season.cos(), -season.sin() * self.obliquity.cos(), season.sin() * self.obliquity.sin());
+        // The planet turns with the mean anomaly, so the star drifts in longitude by nu - M
+        let spin = mean + self.periastron + PI;
+        let x = star.0 * spin.cos() + star.1 * spin.sin();
+        let y = -star.0 * spin.sin() + star.1 * spin.cos();
+        let scale = ((1.0 + e * nu.cos()) / (1.0 - e * e)).powi(2);
+        Ok(Illumination{substellar: coords::Coordinate::from_cart(x, y, star.2)?, scale, rule: InsolationRule::default()})
+    }
+}
+
+/// What drives the incident flux over a run
+#[derive(Clone,Copy,Debug,PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Forcing {
+    Fixed(Illumination),
+    Orbit(Orbit)
+}
+
+impl Default for Forcing {
+    fn default() -> Forcing {
+        Forcing::Fixed(Illumination::default())
+    }
+}
+
+impl Forcing {
+    /// Illumination at model time `time`; an orbit gives the default `InsolationRule`
+    pub fn illumination(self:&Forcing, time: f64) -> Result<Illumination,Error> {
+        match self {
+            Forcing::Fixed(light) => Ok(*light),
+            Forcing::Orbit(orbit) => orbit.illumination(time)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_orbit_illumination() {
+        let lat = |light: &Illumination| FRAC_PI_2 - light.substellar.theta;
+
+        // A circular orbit without obliquity looks like the fixed forcing
+        let circular = Orbit{period: 10.0, eccentricity: 0.0, obliquity: 0.0, periastron: 0.0, true_anomaly: 0.0};
+        for t in [0.0, 2.5, 7.0] {
+            let light = circular.illumination(t).unwrap();
+            assert!(light.substellar.angle_between(&Illumination::default().substellar) < 1e-12);
+            assert!((light.scale - 1.0).abs() < 1e-12);
+        }
+
+        // Seasons: the substellar latitude swings between plus and minus the obliquity
+        let obliquity = 0.4;
+        let tilted = Orbit{obliquity, ..circular};
+        assert!(lat(&tilted.illumination(0.0).unwrap()).abs() < 1e-12);
+        assert!((lat(&tilted.illumination(2.5).unwrap()) - obliquity).abs() < 1e-12);
+        assert!((lat(&tilted.illumination(7.5).unwrap()) + obliquity).abs() < 1e-12);
+
+        // Eccentricity: brightest at periastron, by (1+e)^2/(1-e^2)^2, and the star librates in longitude
+        let eccentric = Orbit{eccentricity: 0.3, ..circular};
+        let periastron = eccentric.illumination(0.0).unwrap();
+        let apoastron = eccentric.illumination(5.0).unwrap();
+        assert!((periastron.scale - 1.0 / 0.7f64.powi(2)).abs() < 1e-12);
+        assert!((apoastron.scale - 1.0 / 1.3f64.powi(2)).abs() < 1e-12);
+        assert!(periastron.substellar.phi.abs() < 1e-12 && apoastron.substellar.phi.abs() < 1e-9);
+        assert!(eccentric.illumination(1.0).unwrap().substellar.phi > 0.1);
+
+        assert!(Orbit{eccentricity: 1.0, ..circular}.illumination(0.0).is_err());
+    }
+
+    #[test]
+    fn test_exact_insolation() {
+        let network = crate::pgen::init_mesh(crate::meshgen::MeshType::Goldberg(2), crate::pgen::InitialCondition::Constant(0.0)).unwrap();
+        // The day side of the unit sphere receives pi times the flux, wherever the star is
+        for (lat, lon) in [(0.0, 0.0), (0.3, 1.0), (-1.2, -2.5), (FRAC_PI_2, 0.0)] {
+            let centroid = Illumination::overhead(lat, lon).unwrap();
+            assert_eq!(centroid.rule, InsolationRule::Centroid);
+            let exact = Illumination{rule: InsolationRule::Exact, ..centroid};
+            let total = |light: &Illumination| network.cells.iter().map(|c| light.flux_over(&c.polygon)).sum::<f64>();
+            assert!((total(&exact) - PI).abs() < 1e-10);
+            assert!((total(&centroid) - PI).abs() > 1e-4);
+            // Away from the terminator the two rules agree to second order in the cell size
+            for cell in network.cells.iter() {
+                if exact.flux_at(&cell.center()) > 0.5 {
+                    let area = cell.polygon.area();
+                    assert!((exact.flux_over(&cell.polygon) - centroid.flux_over(&cell.polygon)).abs() < 0.01 * area);
+                }
+            }
+        }
+    }
+}
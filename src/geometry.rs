@@ -1,7 +1,6 @@
 
-use std::f32::consts::PI;
-
 use super::coords;
+use super::error::Error;
 
 pub struct GreatCircle {
     x: f64,
@@ -15,18 +14,19 @@ impl GreatCircle {
         
         GreatCircle{ x:x/mag, y:y/mag, z:z/mag }
     }
-    pub fn from_coords(a: coords::Coordinate, b: coords::Coordinate) -> GreatCircle {
-        let normal = a.cross_normalized(&b).unwrap();
-        let mag = normal.dot(&normal).unwrap().sqrt();    
-        let (x,y,z) = normal.cart().unwrap();
-        GreatCircle{ x:x/mag, y:y/mag, z:z/mag }
+    pub fn from_coords(a: coords::Coordinate, b: coords::Coordinate) -> Result<GreatCircle, Error> {
+        let normal = a.cross_normalized(&b)?;
+        let mag = normal.dot(&normal)?.sqrt();
+        let (x,y,z) = normal.cart()?;
+        Ok(GreatCircle{ x:x/mag, y:y/mag, z:z/mag })
     }
-    pub fn nhat(&self) -> coords::Coordinate {
-        coords::Coordinate::from_cart(self.x, self.y, self.z).unwrap()
+    /// Unit normal of the circle; fails for a circle built by `new` from a zero or `NaN` vector
+    pub fn nhat(&self) -> Result<coords::Coordinate, Error> {
+        coords::Coordinate::from_cart(self.x, self.y, self.z)
     }
-    pub fn inclination(&self) -> f64 {
-        let zhat = coords::Coordinate::from_cart(0.0,0.0,1.0).unwrap();
-        self.nhat().dot(&zhat).unwrap().acos()
+    pub fn inclination(&self) -> Result<f64, Error> {
+        let zhat = coords::Coordinate{phi: 0.0, theta: 0.0};
+        Ok(self.nhat()?.dot(&zhat)?.acos())
     }
 
 }
@@ -1,17 +1,54 @@
+use std::collections::HashMap;
+use std::sync::Arc;
 
-use std::fmt::Display;
-use super::coords;
+use super::{coords, surface};
+use super::error::Error;
 
-#[derive(Clone)]
+#[derive(Clone,Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(try_from = "RawGridCell"))]
 pub struct GridCell{
     pub polygon:coords::Polygon,
-    pub value:f64
+    pub value:f64,
+    pub surface:surface::Surface
+}
+
+/// Unchecked form of `GridCell` that deserialization goes through
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct RawGridCell{
+    polygon:coords::Polygon,
+    value:f64,
+    #[serde(default)]
+    surface:surface::Surface
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<RawGridCell> for GridCell{
+    type Error = Error;
+    fn try_from(raw:RawGridCell)->Result<GridCell,Error>{
+        raw.surface.check()?;
+        Ok(GridCell{surface:raw.surface,..GridCell::new(raw.polygon,raw.value)?})
+    }
 }
 
 impl GridCell{
-    pub fn new(polygon:coords::Polygon,value:f64)->GridCell{
-        if value < 0.0 {panic!{"Cell value cannot be negative"}}
-        GridCell{polygon:polygon,value:value}
+    /// A cell with the default `surface::Surface`.
+    ///
+    /// Fails if the polygon has fewer than three nodes or no centroid.
+    pub fn new(polygon:coords::Polygon,value:f64)->Result<GridCell,Error>{
+        if value < 0.0 { return Err(Error::NegativeTemperature{cell: None, value}) }
+        if polygon.nodes.len() < 3 { return Err(Error::InvalidArgument("a cell needs at least three nodes")) }
+        polygon.center()?;
+        Ok(GridCell{polygon,value,surface:surface::Surface::default()})
+    }
+    /// Copy of this cell holding `value`, with the same polygon and surface
+    pub fn with_value(self:&GridCell,value:f64)->Result<GridCell,Error>{
+        if value < 0.0 { return Err(Error::NegativeTemperature{cell: None, value}) }
+        Ok(GridCell{value,..self.clone()})
+    }
+    /// Centroid of the polygon, which `new` checked exists
+    pub fn center(self:&GridCell)->coords::Coordinate{
+        self.polygon.center().expect("GridCell::new checks that the polygon has a centroid")
     }
 }
 
@@ -21,41 +58,286 @@ impl PartialEq for GridCell{
     }
 }
 
+/// An edge of the mesh, stored as a pair of vertex indices
+/// together with the cells it separates.
+#[derive(Clone,Debug)]
+pub struct MeshEdge{
+    pub a:usize,
+    pub b:usize,
+    pub cells:Vec<usize>
+}
+
+/// Hashable key for a vertex. `0.0` and `-0.0` compare equal,
+/// so they must also hash the same.
+fn vertex_key(node:&coords::Coordinate)->(u64,u64){
+    let bits = |x:f64| if x == 0.0 { 0 } else { x.to_bits() };
+    (bits(node.phi),bits(node.theta))
+}
+
+fn edge_key(a:usize,b:usize)->(usize,usize){
+    if a < b { (a,b) } else { (b,a) }
+}
+
+/// Shared vertex table, edge table and cell adjacency of a mesh.
+///
+/// Built once from a list of polygons. Vertices are identified by exact
+/// equality of their coordinates, the same rule `coords::Edge` uses.
+#[derive(Debug,Default)]
+pub struct MeshTopology{
+    pub vertices:Vec<coords::Coordinate>,
+    pub edges:Vec<MeshEdge>,
+    /// Vertex indices of each cell, in polygon order
+    pub faces:Vec<Vec<usize>>,
+    /// Edge indices of each cell, in the order of `Polygon::to_edges`
+    pub cell_edges:Vec<Vec<usize>>,
+    /// Indices of the cells sharing an edge with each cell
+    pub neighbors:Vec<Vec<usize>>,
+    /// Indices of the cells touching each vertex
+    pub vertex_cells:Vec<Vec<usize>>,
+    vertex_lookup:HashMap<(u64,u64),usize>,
+    edge_lookup:HashMap<(usize,usize),usize>
+}
+
+impl MeshTopology{
+    pub fn from_polygons(polygons:&[coords::Polygon])->MeshTopology{
+        let mut topology = MeshTopology::default();
+        for (i,polygon) in polygons.iter().enumerate(){
+            let face:Vec<usize> = polygon.nodes.iter().map(|node| topology.insert_vertex(node)).collect();
+            for &v in face.iter(){
+                topology.vertex_cells[v].push(i);
+            }
+            let mut cell_edges:Vec<usize> = Vec::with_capacity(face.len());
+            for j in 0..face.len(){
+                let a = face[j];
+                let b = face[(j+1) % face.len()];
+                let e = topology.insert_edge(a,b);
+                topology.edges[e].cells.push(i);
+                cell_edges.push(e);
+            }
+            topology.faces.push(face);
+            topology.cell_edges.push(cell_edges);
+        }
+        for i in 0..topology.faces.len(){
+            let mut neighbors:Vec<usize> = Vec::new();
+            for &e in topology.cell_edges[i].iter(){
+                for &c in topology.edges[e].cells.iter(){
+                    if c != i && !neighbors.contains(&c){
+                        neighbors.push(c);
+                    }
+                }
+            }
+            topology.neighbors.push(neighbors);
+        }
+        topology
+    }
+    fn insert_vertex(self:&mut MeshTopology,node:&coords::Coordinate)->usize{
+        let key = vertex_key(node);
+        if let Some(&v) = self.vertex_lookup.get(&key){
+            return v;
+        }
+        let v = self.vertices.len();
+        self.vertices.push(*node);
+        self.vertex_cells.push(Vec::new());
+        self.vertex_lookup.insert(key,v);
+        v
+    }
+    fn insert_edge(self:&mut MeshTopology,a:usize,b:usize)->usize{
+        let key = edge_key(a,b);
+        if let Some(&e) = self.edge_lookup.get(&key){
+            return e;
+        }
+        let e = self.edges.len();
+        self.edges.push(MeshEdge{a,b,cells:Vec::new()});
+        self.edge_lookup.insert(key,e);
+        e
+    }
+    pub fn find_vertex(self:&MeshTopology,node:&coords::Coordinate)->Option<usize>{
+        self.vertex_lookup.get(&vertex_key(node)).copied()
+    }
+    pub fn find_edge(self:&MeshTopology,edge:&coords::Edge)->Option<usize>{
+        let a = self.find_vertex(&edge.a)?;
+        let b = self.find_vertex(&edge.b)?;
+        self.edge_lookup.get(&edge_key(a,b)).copied()
+    }
+    /// Given an edge of `cell`, return the cells on the other side of it
+    pub fn across(self:&MeshTopology,cell:usize,edge:usize)->impl Iterator<Item=usize> + '_{
+        self.edges[edge].cells.iter().copied().filter(move |&c| c != cell)
+    }
+}
+
+/// Index of a field of a `GridNetwork`.
+///
+/// Field `VALUE` is the values of the cells themselves, the temperature the
+/// energy balance evolves. Further fields, such as a second temperature, a
+/// tracer or an ice fraction, are added with `GridNetwork::add_field`.
+pub type FieldId = usize;
+pub const VALUE: FieldId = 0;
+/// Name of field `VALUE`
+pub static VALUE_NAME: &str = "value";
+/// Names the CSV, VTK and NetCDF outputs already use besides the fields
+pub static RESERVED_NAMES: [&str; 9] = ["cell", "lon", "lat", "area", "time", "step", "dt", "cfl_limiter", "cfl_dt"];
 
+/// A named field with one value per cell
+#[derive(Clone,Debug,PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Field{
+    pub name:String,
+    pub values:Vec<f64>
+}
+
+/// Cells of a mesh with their shared topology and any fields beyond the cell values.
+///
+/// Only the cells and fields are serialized; the topology is rebuilt from them
+/// when the network is deserialized.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(try_from = "RawGridNetwork"))]
 pub struct GridNetwork{
-    pub cells:Vec<GridCell>
+    pub cells:Vec<GridCell>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    topology:Arc<MeshTopology>,
+    /// Fields `1..`, in order
+    fields:Vec<Field>
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct RawGridNetwork{
+    cells:Vec<GridCell>,
+    #[serde(default)]
+    fields:Vec<Field>
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<RawGridNetwork> for GridNetwork{
+    type Error = Error;
+    fn try_from(raw:RawGridNetwork)->Result<GridNetwork,Error>{
+        let mut network = GridNetwork::new(raw.cells);
+        for field in raw.fields{
+            network.add_field(&field.name,field.values)?;
+        }
+        Ok(network)
+    }
 }
 
 impl GridNetwork{
     pub fn new(cells:Vec<GridCell>)->GridNetwork{
-        GridNetwork{cells:cells}
+        let polygons:Vec<coords::Polygon> = cells.iter().map(|c| c.polygon.clone()).collect();
+        let topology = Arc::new(MeshTopology::from_polygons(&polygons));
+        GridNetwork{cells,topology,fields:Vec::new()}
     }
-    pub fn query_node(self:&GridNetwork,node: &coords::Coordinate)->Vec<&GridCell>{
-        // returns a copy of the cells that contain the node
-        let mut cells:Vec<&GridCell> = Vec::new();
-        for cell in self.cells.iter(){
-            if cell.polygon.nodes.contains(&node){
-                cells.push(cell);
+    /// Create a network that reuses an existing topology.
+    ///
+    /// `cells` must be in the same order as the cells the topology was built from;
+    /// a different number of them is an `InvalidArgument`.
+    pub fn with_topology(cells:Vec<GridCell>,topology:Arc<MeshTopology>)->Result<GridNetwork,Error>{
+        if cells.len() != topology.faces.len() {
+            return Err(Error::InvalidArgument("cell count does not match the topology"));
+        }
+        Ok(GridNetwork{cells,topology,fields:Vec::new()})
+    }
+    /// Copy of this network with new `cells`, keeping its topology and other fields
+    pub fn with_cells(self:&GridNetwork,cells:Vec<GridCell>)->Result<GridNetwork,Error>{
+        Ok(GridNetwork{fields:self.fields.clone(),..GridNetwork::with_topology(cells,self.topology.clone())?})
+    }
+    /// Add a field holding `values`, one per cell, and return its index.
+    ///
+    /// Names are made of letters, digits, `_` and `-` and begin with a letter
+    /// or `_`, so they can be used as column and array names in every output
+    /// format, and may not be one of `RESERVED_NAMES` or begin with `Mesh2`.
+    pub fn add_field(self:&mut GridNetwork,name:&str,values:Vec<f64>)->Result<FieldId,Error>{
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            return Err(Error::InvalidArgument("field names are made of letters, digits, _ and -"));
+        }
+        if !name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+            return Err(Error::InvalidArgument("field names begin with a letter or _"));
+        }
+        if RESERVED_NAMES.contains(&name) || name.starts_with("Mesh2") {
+            return Err(Error::InvalidArgument("that name is used by the outputs for something other than a field"));
+        }
+        if values.len() != self.cells.len() {
+            return Err(Error::InvalidArgument("a field needs one value per cell"));
+        }
+        if self.field_id(name).is_some() {
+            return Err(Error::InvalidArgument("a field of that name already exists"));
+        }
+        self.fields.push(Field{name:name.to_string(),values});
+        Ok(self.fields.len())
+    }
+    /// Number of fields, counting `VALUE`
+    pub fn n_fields(self:&GridNetwork)->usize{
+        self.fields.len() + 1
+    }
+    pub fn field_id(self:&GridNetwork,name:&str)->Option<FieldId>{
+        if name == VALUE_NAME { return Some(VALUE); }
+        self.fields.iter().position(|f| f.name == name).map(|k| k + 1)
+    }
+    pub fn field_name(self:&GridNetwork,field:FieldId)->&str{
+        if field == VALUE { VALUE_NAME } else { &self.fields[field - 1].name }
+    }
+    /// Value of `field` in cell `i`
+    pub fn value(self:&GridNetwork,field:FieldId,i:usize)->f64{
+        if field == VALUE { self.cells[i].value } else { self.fields[field - 1].values[i] }
+    }
+    /// Values of `field` in every cell
+    pub fn values(self:&GridNetwork,field:FieldId)->Vec<f64>{
+        if field == VALUE { self.cells.iter().map(|c| c.value).collect() } else { self.fields[field - 1].values.clone() }
+    }
+    /// Replace the values of `field`. The cell values, `VALUE`, may not be negative.
+    pub fn set_values(self:&mut GridNetwork,field:FieldId,values:Vec<f64>)->Result<(),Error>{
+        if values.len() != self.cells.len() {
+            return Err(Error::InvalidArgument("a field needs one value per cell"));
+        }
+        if field == VALUE {
+            if let Some(&value) = values.iter().find(|&&v| v < 0.0) {
+                return Err(Error::NegativeTemperature{cell: None, value});
+            }
+            for (cell,value) in self.cells.iter_mut().zip(values){
+                cell.value = value;
             }
         }
-        cells
+        else {
+            self.fields[field - 1].values = values;
+        }
+        Ok(())
     }
-    pub fn query_edge(self:&GridNetwork,edge:&coords::Edge)->Vec<&GridCell>{
-        // returns a copy of the cells that contain the edge
-        let mut cells:Vec<&GridCell> = Vec::new();
-        for cell in self.cells.iter(){
-            if cell.polygon.to_edges().contains(&edge){
-                cells.push(cell);
+    pub fn topology(self:&GridNetwork)->&Arc<MeshTopology>{
+        &self.topology
+    }
+    /// Indices of the cells that share an edge with cell `i`
+    pub fn neighbors(self:&GridNetwork,i:usize)->&[usize]{
+        &self.topology.neighbors[i]
+    }
+    /// Smallest great-circle distance between the centers of two neighboring cells
+    pub fn min_cell_spacing(self:&GridNetwork)->f64{
+        let centers:Vec<coords::Coordinate> = self.cells.iter().map(|c| c.center()).collect();
+        let mut min:f64 = f64::INFINITY;
+        for edge in self.topology.edges.iter(){
+            for (i,&a) in edge.cells.iter().enumerate(){
+                for &b in edge.cells[i+1..].iter(){
+                    min = min.min(centers[a].angle_between(&centers[b]));
+                }
             }
         }
-        cells
+        min
+    }
+    pub fn query_node(self:&GridNetwork,node: &coords::Coordinate)->Vec<&GridCell>{
+        // returns references to the cells that contain the node
+        match self.topology.find_vertex(node){
+            Some(v) => self.topology.vertex_cells[v].iter().map(|&c| &self.cells[c]).collect(),
+            None => Vec::new()
+        }
+    }
+    pub fn query_edge(self:&GridNetwork,edge:&coords::Edge)->Vec<&GridCell>{
+        // returns references to the cells that contain the edge
+        match self.topology.find_edge(edge){
+            Some(e) => self.topology.edges[e].cells.iter().map(|&c| &self.cells[c]).collect(),
+            None => Vec::new()
+        }
     }
     pub fn query_neighbors(self:&GridNetwork,cell: &GridCell)->Vec<&GridCell>{
         // returns references to the cells that are neighbors of the cell
         let mut cells:Vec<&GridCell> = Vec::new();
         for edge in cell.polygon.to_edges(){
-            let neighbors = self.query_edge(&edge);
-            for neighbor in neighbors{
+            for neighbor in self.query_edge(&edge){
                 if neighbor != cell{
                     cells.push(neighbor);
                 }
@@ -63,13 +345,10 @@ impl GridNetwork{
         }
         cells
     }
+    /// The cell of this network equal to `cell`, or `Err(())` if there is none
+    #[allow(clippy::result_unit_err)]
     pub fn query_cell(self:&GridNetwork,cell:GridCell)->Result<&GridCell,()> {
-        for _cell in self.cells.iter() {
-            if cell == *_cell {
-                return Result::Ok(_cell);
-            }
-        }
-        Result::Err(())
+        self.cells.iter().find(|_cell| cell == **_cell).ok_or(())
     }
     pub fn max_value(self:&GridNetwork)->f64{
         let mut max:f64 = 0.0;
@@ -81,7 +360,7 @@ impl GridNetwork{
         max
     }
     pub fn min_value(self:&GridNetwork)->f64{
-        let mut min:f64 = std::f64::MAX;
+        let mut min:f64 = f64::MAX;
         for cell in self.cells.iter(){
             if cell.value < min{
                 min = cell.value;
@@ -97,3 +376,135 @@ impl GridNetwork{
         sum / self.cells.len() as f64
     }
 }
+
+type Vector = (f64,f64,f64);
+
+fn dot(a:Vector,b:Vector)->f64{
+    a.0*b.0 + a.1*b.1 + a.2*b.2
+}
+
+/// Finds the cell of a `GridNetwork` that contains a point.
+///
+/// Each cell is stored as the inward normals of its edge planes, so a
+/// point is inside a cell when it is on the inner side of every edge. A
+/// search walks from a starting cell towards the point across neighbors,
+/// which makes looking up nearby points one after another cheap.
+pub struct CellLocator<'a>{
+    network:&'a GridNetwork,
+    centers:Vec<Vector>,
+    normals:Vec<Vec<Vector>>
+}
+
+impl<'a> CellLocator<'a>{
+    pub fn new(network:&'a GridNetwork)->Result<CellLocator<'a>,Error>{
+        let mut centers = Vec::with_capacity(network.cells.len());
+        let mut normals = Vec::with_capacity(network.cells.len());
+        for cell in network.cells.iter(){
+            let center = cell.center().cart()?;
+            let nodes = cell.polygon.nodes.iter().map(|n| n.cart()).collect::<Result<Vec<Vector>,Error>>()?;
+            let mut planes = Vec::with_capacity(nodes.len());
+            for (i,&a) in nodes.iter().enumerate(){
+                let b = nodes[(i+1)%nodes.len()];
+                let n = (a.1*b.2 - a.2*b.1, a.2*b.0 - a.0*b.2, a.0*b.1 - a.1*b.0);
+                let mag = dot(n,n).sqrt();
+                // Repeated nodes, like the poles of a lat-lon mesh, do not bound the cell
+                if mag < 1e-12 { continue; }
+                let sign = if dot(n,center) < 0.0 { -1.0 } else { 1.0 };
+                planes.push((sign*n.0/mag, sign*n.1/mag, sign*n.2/mag));
+            }
+            centers.push(center);
+            normals.push(planes);
+        }
+        Ok(CellLocator{network,centers,normals})
+    }
+    fn contains(self:&CellLocator<'a>,cell:usize,point:Vector)->bool{
+        self.normals[cell].iter().all(|&n| dot(n,point) >= -1e-12)
+    }
+    /// Index of the cell containing `point`, starting the search at `hint`.
+    ///
+    /// Returns `None` if no cell contains the point, which can only happen
+    /// on meshes that do not cover the whole sphere.
+    pub fn locate(self:&CellLocator<'a>,point:&coords::Coordinate,hint:Option<usize>)->Result<Option<usize>,Error>{
+        let p = point.cart()?;
+        if self.centers.is_empty() { return Ok(None); }
+        let mut current = hint.filter(|&h| h < self.centers.len()).unwrap_or(0);
+        loop {
+            if self.contains(current,p) { return Ok(Some(current)); }
+            let closer = self.network.neighbors(current).iter().copied()
+                .max_by(|&a,&b| dot(self.centers[a],p).total_cmp(&dot(self.centers[b],p)))
+                .filter(|&n| dot(self.centers[n],p) > dot(self.centers[current],p));
+            match closer {
+                Some(n) => current = n,
+                None => break
+            }
+        }
+        // The walk can stop next to the containing cell rather than in it
+        if let Some(&n) = self.network.neighbors(current).iter().find(|&&n| self.contains(n,p)) {
+            return Ok(Some(n));
+        }
+        Ok((0..self.centers.len()).find(|&i| self.contains(i,p)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meshgen::icoshedron;
+
+    #[test]
+    fn test_icosahedron_topology() {
+        let topology = MeshTopology::from_polygons(&icoshedron(0).unwrap());
+        assert_eq!(topology.vertices.len(), 12);
+        assert_eq!(topology.edges.len(), 30);
+        assert_eq!(topology.faces.len(), 20);
+        for edge in topology.edges.iter() {
+            assert_eq!(edge.cells.len(), 2);
+        }
+        for (i, neighbors) in topology.neighbors.iter().enumerate() {
+            assert_eq!(neighbors.len(), 3);
+            for &n in neighbors.iter() {
+                assert!(topology.neighbors[n].contains(&i));
+            }
+        }
+        for cells in topology.vertex_cells.iter() {
+            assert_eq!(cells.len(), 5);
+        }
+    }
+
+    #[test]
+    fn test_query_edge_matches_scan() {
+        let polygons = icoshedron(1).unwrap();
+        let network = GridNetwork::new(polygons.iter().map(|p| GridCell::new(p.clone(), 0.0).unwrap()).collect());
+        for (i, cell) in network.cells.iter().enumerate() {
+            for edge in cell.polygon.to_edges() {
+                let found = network.query_edge(&edge);
+                let scanned: Vec<&GridCell> = network.cells.iter().filter(|c| c.polygon.to_edges().contains(&edge)).collect();
+                assert_eq!(found.len(), scanned.len());
+                assert!(found.contains(&cell));
+            }
+            assert_eq!(network.query_neighbors(cell).len(), network.neighbors(i).len());
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() {
+        let polygons = icoshedron(1).unwrap();
+        let mut network = GridNetwork::new(polygons.iter().enumerate().map(|(i, p)| GridCell::new(p.clone(), i as f64).unwrap()).collect());
+        let tracer = network.add_field("tracer", (0..polygons.len()).map(|i| -(i as f64)).collect()).unwrap();
+        let json = serde_json::to_string(&network).unwrap();
+        let read: GridNetwork = serde_json::from_str(&json).unwrap();
+        assert_eq!(read.cells.len(), network.cells.len());
+        for (a, b) in read.cells.iter().zip(network.cells.iter()) {
+            assert!(a == b && a.value == b.value);
+        }
+        assert_eq!(read.topology().neighbors, network.topology().neighbors);
+        assert_eq!(read.field_id("tracer"), Some(tracer));
+        assert_eq!(read.values(tracer), network.values(tracer));
+
+        // Deserialization checks the same invariants as the constructors
+        assert!(serde_json::from_str::<coords::Coordinate>(r#"{"phi": 0.0, "theta": 4.0}"#).is_err());
+        let cell = serde_json::to_string(&network.cells[0]).unwrap().replace(r#""value":0.0"#, r#""value":-1.0"#);
+        assert!(serde_json::from_str::<GridCell>(&cell).is_err());
+    }
+}
@@ -0,0 +1,132 @@
+//! Hysteresis loops of the ice-albedo feedback
+//!
+//! With `surface::IceAlbedo` a planet can have both a warm and a snowball
+//! equilibrium under the same starlight. `sweep` raises the stellar flux in
+//! steps and lowers it again, letting the run settle at each step from the
+//! state the one before left, so each branch is followed until it vanishes.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use log::info;
+
+use super::{driver, forcing, grid};
+use super::error::Error;
+
+/// State the run settled into at one stellar flux
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct LoopPoint {
+    /// Stellar flux, as the scale of the fixed `forcing::Illumination`
+    pub scale: f64,
+    /// Whether the flux was being raised
+    pub rising: bool,
+    /// Area-weighted means of the cell values, ice cover and albedo
+    pub mean_value: f64,
+    pub ice_fraction: f64,
+    pub albedo: f64,
+    /// Why the run at this flux stopped; anything but a balance means it may not have settled
+    pub reason: driver::StopReason
+}
+
+impl LoopPoint {
+    /// Whether the run at this flux stopped because it reached a balance
+    pub fn settled(self:&LoopPoint) -> bool {
+        matches!(self.reason, driver::StopReason::EnergyBalanced(_) | driver::StopReason::ChangeBelowTolerance(_))
+    }
+}
+
+/// Area-weighted mean of `f` over the cells
+fn mean(network: &grid::GridNetwork, f: impl Fn(&grid::GridCell) -> f64) -> f64 {
+    let total: f64 = network.cells.iter().map(|c| c.polygon.area()).sum();
+    network.cells.iter().map(|c| c.polygon.area() * f(c)).sum::<f64>() / total
+}
+
+/// `n` stellar fluxes evenly spaced from `min` to `max`
+pub fn flux_steps(min: f64, max: f64, n: usize) -> Vec<f64> {
+    match n {
+        0 => Vec::new(),
+        1 => vec![min],
+        _ => (0..n).map(|i| min + (max - min) * i as f64 / (n - 1) as f64).collect()
+    }
+}
+
+/// Run `sim` to `criteria` at each flux of `scales` in turn, then back down
+/// them in reverse, and record where it settled.
+///
+/// The forcing must be a fixed substellar point; its scale is replaced at
+/// every step of the sweep and left at the last one. `max_time` limits the
+/// model time spent at each flux, not the time of the whole sweep.
+pub fn sweep(sim: &mut driver::Simulation, scales: &[f64], criteria: &driver::StoppingCriteria) -> Result<Vec<LoopPoint>,Error> {
+    let forcing::Forcing::Fixed(light) = sim.forcing else {
+        return Err(Error::InvalidArgument("A hysteresis sweep needs a fixed substellar point"));
+    };
+    let rising = scales.iter().map(|&s| (s, true));
+    let falling = scales.iter().rev().skip(1).map(|&s| (s, false));
+    let mut points = Vec::with_capacity(2 * scales.len());
+    for (scale, rising) in rising.chain(falling) {
+        sim.forcing = forcing::Forcing::Fixed(forcing::Illumination{scale, ..light});
+        let stop = driver::StoppingCriteria{max_time: criteria.max_time.map(|t| sim.time + t), ..criteria.clone()};
+        let summary = sim.run(&stop)?;
+        let network = &sim.network;
+        let point = LoopPoint{
+            scale,
+            rising,
+            mean_value: mean(network, |c| c.value),
+            ice_fraction: mean(network, |c| c.surface.ice_fraction(c.value)),
+            albedo: mean(network, |c| c.surface.albedo_at(c.value)),
+            reason: summary.reason
+        };
+        info!("Stellar flux {} ({}): mean value {}, ice cover {}",scale,if rising { "rising" } else { "falling" },point.mean_value,point.ice_fraction);
+        points.push(point);
+    }
+    Ok(points)
+}
+
+/// Write one row per point of the loop, in the order they were visited
+pub fn write_csv(points: &[LoopPoint], path: &Path) -> std::io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "scale,direction,mean_value,ice_fraction,albedo,settled")?;
+    for p in points.iter() {
+        let direction = if p.rising { "rising" } else { "falling" };
+        writeln!(out, "{},{},{},{},{},{}", p.scale, direction, p.mean_value, p.ice_fraction, p.albedo, p.settled())?;
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{integrate, pgen, surface};
+    use crate::meshgen::MeshType;
+
+    #[test]
+    fn test_snowball_hysteresis() {
+        // Strong diffusion keeps the planet close to uniform, where a warm
+        // state needs scale * 0.7 / 4 > 0.7^4 and a snowball scale * 0.3 / 4 < 0.7^4
+        let mut network = pgen::init_mesh(MeshType::Geodesic(1), pgen::InitialCondition::Constant(0.3)).unwrap();
+        let ice = surface::IceAlbedo{albedo: 0.7, freeze: 0.7, width: 0.01};
+        surface::apply(&mut network, |_, _, s| *s = surface::Surface{albedo: 0.3, ice: Some(ice), ..Default::default()}).unwrap();
+        let mut sim = driver::Simulation::new(network, 0.0, 20.0, integrate::TimeScheme::BackwardEuler);
+        sim.timestep.fixed_dt = Some(0.5);
+        let criteria = driver::StoppingCriteria{energy_tolerance: Some(0.01), max_steps: Some(2000), ..Default::default()};
+        let points = sweep(&mut sim, &flux_steps(1.0, 4.0, 7), &criteria).unwrap();
+        assert_eq!(points.len(), 13);
+        assert!(points.iter().all(|p| matches!(p.reason, driver::StopReason::EnergyBalanced(_))));
+        // At a flux of 2 the planet is frozen on the way up and warm on the way down
+        let at = |rising: bool| points.iter().find(|p| p.scale == 2.0 && p.rising == rising).unwrap();
+        assert!(at(true).ice_fraction > 0.9);
+        assert!(at(false).ice_fraction < 0.1);
+        assert!(at(false).mean_value > at(true).mean_value);
+        assert!(points[6].ice_fraction < 0.1);
+        assert!(points[12].ice_fraction > 0.9);
+
+        // A time limit applies to each flux in turn
+        let start = sim.time;
+        let criteria = driver::StoppingCriteria{max_time: Some(1.0), ..Default::default()};
+        let points = sweep(&mut sim, &[1.0, 2.0], &criteria).unwrap();
+        assert_eq!(points.len(), 3);
+        assert!(points.iter().all(|p| matches!(p.reason, driver::StopReason::MaxTime(_)) && !p.settled()));
+        assert!((sim.time - start - 3.0).abs() < 1e-9);
+    }
+}
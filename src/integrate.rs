@@ -0,0 +1,328 @@
+//! Time integration of the energy-balance equation
+//!
+//!
+
+use log::{debug,error,info};
+
+use super::{forcing, grid, pgen, sparse::{self, SparseMatrix}};
+use super::error::Error;
+
+/// Relative tolerance on the Newton residual
+static NEWTON_TOLERANCE: f64 = 1e-10;
+static MAX_NEWTON_ITERATIONS: usize = 30;
+/// Relative tolerance of the linear solve inside each Newton iteration
+static LINEAR_TOLERANCE: f64 = 1e-12;
+static MAX_LINEAR_ITERATIONS: usize = 1000;
+
+/// How to advance the mesh by one timestep
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum TimeScheme {
+    /// Explicit forward Euler, as in `pgen::get_next_mesh`
+    ForwardEuler,
+    /// Backward Euler. Every term except the incident flux is implicit.
+    BackwardEuler,
+    /// Implicit diffusion and emission, explicit advection
+    Imex,
+    /// Two-stage strong-stability-preserving Runge-Kutta (Heun's method)
+    SspRk2,
+    /// Three-stage strong-stability-preserving Runge-Kutta of Shu and Osher
+    SspRk3,
+    /// Classic four-stage Runge-Kutta
+    Rk4
+}
+
+impl TimeScheme {
+    /// Order of accuracy in time
+    pub fn order(self:&TimeScheme) -> u32 {
+        match *self {
+            TimeScheme::ForwardEuler | TimeScheme::BackwardEuler | TimeScheme::Imex => 1,
+            TimeScheme::SspRk2 => 2,
+            TimeScheme::SspRk3 => 3,
+            TimeScheme::Rk4 => 4
+        }
+    }
+}
+
+/// Advance `network` by `dt` using `scheme`.
+///
+/// The implicit schemes are not bound by the diffusion and source limits
+/// of `pgen::get_timestep`. `Imex` is still bound by the advection limit.
+/// Fields other than `grid::VALUE` are advanced by `step_fields`.
+pub fn step(network: &grid::GridNetwork, eps1: f64, eps2: f64, light: &forcing::Illumination, dt: f64, scheme: TimeScheme) -> Result<grid::GridNetwork,Error> {
+    let values = match scheme {
+        TimeScheme::ForwardEuler => {
+            let mut values = Vec::with_capacity(network.cells.len());
+            for i in 0..network.cells.len() {
+                values.push(pgen::get_next_value(i,network,eps1,eps2,light,dt)?);
+            }
+            values
+        },
+        TimeScheme::BackwardEuler => implicit_values(network,eps1,eps2,light,dt,true)?,
+        TimeScheme::Imex => implicit_values(network,eps1,eps2,light,dt,false)?,
+        TimeScheme::SspRk2 => {
+            let u0 = values_of(network);
+            let u1 = euler_stage(network,eps1,eps2,light,dt)?;
+            let u2 = euler_stage(&stage(network,u1)?,eps1,eps2,light,dt)?;
+            combine(&[(0.5,&u0),(0.5,&u2)])
+        },
+        TimeScheme::SspRk3 => {
+            let u0 = values_of(network);
+            let u1 = euler_stage(network,eps1,eps2,light,dt)?;
+            let u1 = euler_stage(&stage(network,u1)?,eps1,eps2,light,dt)?;
+            let u2 = combine(&[(0.75,&u0),(0.25,&u1)]);
+            let u2 = euler_stage(&stage(network,u2)?,eps1,eps2,light,dt)?;
+            combine(&[(1.0/3.0,&u0),(2.0/3.0,&u2)])
+        },
+        TimeScheme::Rk4 => {
+            let u0 = values_of(network);
+            let k1 = tendency(network,eps1,eps2,light)?;
+            let k2 = tendency(&stage(network,combine(&[(1.0,&u0),(dt/2.0,&k1)]))?,eps1,eps2,light)?;
+            let k3 = tendency(&stage(network,combine(&[(1.0,&u0),(dt/2.0,&k2)]))?,eps1,eps2,light)?;
+            let k4 = tendency(&stage(network,combine(&[(1.0,&u0),(dt,&k3)]))?,eps1,eps2,light)?;
+            combine(&[(1.0,&u0),(dt/6.0,&k1),(dt/3.0,&k2),(dt/3.0,&k3),(dt/6.0,&k4)])
+        }
+    };
+    let mut next = stage(network,values)?;
+    step_fields(network,&mut next,eps1,eps2,dt,scheme)?;
+    Ok(next)
+}
+
+/// Advance the fields of `network` other than `grid::VALUE` by `dt` with
+/// `scheme`, storing them in `next`.
+///
+/// They are passive tracers: carried by the same advection and diffusion as
+/// the heat, as in `pgen::transport_tendency`, with no sources and no heat capacity.
+pub fn step_fields(network: &grid::GridNetwork, next: &mut grid::GridNetwork, eps1: f64, eps2: f64, dt: f64, scheme: TimeScheme) -> Result<(),Error> {
+    if network.n_fields() == 1 {
+        return Ok(());
+    }
+    let areas: Vec<f64> = network.cells.iter().map(|c| c.polygon.area()).collect();
+    let transport = transport_matrix(network,eps1,eps2)?;
+    let rate = |q: &Vec<f64>| -> Vec<f64> { transport.matvec(q).iter().zip(areas.iter()).map(|(f, a)| f / a).collect() };
+    let euler = |q: &Vec<f64>| combine(&[(1.0,q),(dt,&rate(q))]);
+    // Imex splits the operator into implicit diffusion and explicit advection
+    let split = match scheme {
+        TimeScheme::Imex => Some((transport_matrix(network,0.0,eps2)?, transport_matrix(network,eps1,0.0)?)),
+        _ => None
+    };
+    for field in 1..network.n_fields() {
+        let q0 = network.values(field);
+        let q = match scheme {
+            TimeScheme::ForwardEuler => euler(&q0),
+            TimeScheme::BackwardEuler => implicit_field(&transport,&areas,&q0,&vec![0.0; q0.len()],dt)?,
+            TimeScheme::Imex => {
+                let (diffusion, advection) = split.as_ref().expect("built for Imex above");
+                implicit_field(diffusion,&areas,&q0,&advection.matvec(&q0),dt)?
+            },
+            TimeScheme::SspRk2 => combine(&[(0.5,&q0),(0.5,&euler(&euler(&q0)))]),
+            TimeScheme::SspRk3 => {
+                let u1 = euler(&euler(&q0));
+                let u2 = euler(&combine(&[(0.75,&q0),(0.25,&u1)]));
+                combine(&[(1.0/3.0,&q0),(2.0/3.0,&u2)])
+            },
+            TimeScheme::Rk4 => {
+                let k1 = rate(&q0);
+                let k2 = rate(&combine(&[(1.0,&q0),(dt/2.0,&k1)]));
+                let k3 = rate(&combine(&[(1.0,&q0),(dt/2.0,&k2)]));
+                let k4 = rate(&combine(&[(1.0,&q0),(dt,&k3)]));
+                combine(&[(1.0,&q0),(dt/6.0,&k1),(dt/3.0,&k2),(dt/3.0,&k3),(dt/6.0,&k4)])
+            }
+        };
+        next.set_values(field,q)?;
+    }
+    Ok(())
+}
+
+/// Linear transport operator `L`: `(L q)_i` is the net advective and
+/// diffusive flux of `q` into cell `i`
+fn transport_matrix(network: &grid::GridNetwork, eps1: f64, eps2: f64) -> Result<SparseMatrix,Error> {
+    let mut transport = SparseMatrix::from_topology(network.topology());
+    for i in 0..network.cells.len() {
+        for side in pgen::side_coefficients(i,network)? {
+            let j = side.neighbor;
+            transport.add(i,i,-eps2 * side.diff - eps1 * side.adv_self)?;
+            transport.add(i,j,eps2 * side.diff - eps1 * side.adv_neighbor)?;
+        }
+    }
+    Ok(transport)
+}
+
+/// Solve `A (q - q0) = dt (L q + explicit)` for a passive field
+fn implicit_field(transport: &SparseMatrix, areas: &[f64], q0: &[f64], explicit: &[f64], dt: f64) -> Result<Vec<f64>,Error> {
+    let system = SparseMatrix {
+        diag: transport.diag.iter().zip(areas.iter()).map(|(d, a)| a - dt * d).collect(),
+        cols: transport.cols.clone(),
+        vals: transport.vals.iter().map(|row| row.iter().map(|v| -dt * v).collect()).collect()
+    };
+    let rhs: Vec<f64> = (0..q0.len()).map(|i| areas[i] * q0[i] + dt * explicit[i]).collect();
+    system.solve(&rhs,q0,LINEAR_TOLERANCE,MAX_LINEAR_ITERATIONS)
+}
+
+/// Rate of change of every cell, as given by `pgen::get_tendency`
+pub fn tendency(network: &grid::GridNetwork, eps1: f64, eps2: f64, light: &forcing::Illumination) -> Result<Vec<f64>,Error> {
+    (0..network.cells.len()).map(|i| pgen::get_tendency(i,network,eps1,eps2,light)).collect()
+}
+
+fn values_of(network: &grid::GridNetwork) -> Vec<f64> {
+    network.cells.iter().map(|c| c.value).collect()
+}
+
+/// A single forward Euler step, which the SSP schemes are built from
+fn euler_stage(network: &grid::GridNetwork, eps1: f64, eps2: f64, light: &forcing::Illumination, dt: f64) -> Result<Vec<f64>,Error> {
+    let k = tendency(network,eps1,eps2,light)?;
+    Ok(combine(&[(1.0,&values_of(network)),(dt,&k)]))
+}
+
+/// Linear combination `sum(c * v)` of equally long vectors
+fn combine(terms: &[(f64,&Vec<f64>)]) -> Vec<f64> {
+    let mut out = vec![0.0; terms[0].1.len()];
+    for (c, v) in terms.iter() {
+        for (o, x) in out.iter_mut().zip(v.iter()) {
+            *o += c * x;
+        }
+    }
+    out
+}
+
+/// Network holding the intermediate `values`, refusing negative temperatures
+fn stage(network: &grid::GridNetwork, values: Vec<f64>) -> Result<grid::GridNetwork,Error> {
+    if let Some(i) = values.iter().position(|&v| v < 0.0) {
+        error!("Negative temperature {} in cell {}",values[i],i);
+        return Err(Error::NegativeTemperature{cell: Some(i), value: values[i]});
+    }
+    with_values(network,values)
+}
+
+/// Copy of `network` holding `values`, sharing its topology and other fields
+pub fn with_values(network: &grid::GridNetwork, values: Vec<f64>) -> Result<grid::GridNetwork,Error> {
+    let cells = network.cells.iter().zip(values).map(|(cell, value)| cell.with_value(value)).collect::<Result<_,_>>()?;
+    network.with_cells(cells)
+}
+
+/// Solve the backward Euler step by Newton iteration.
+///
+/// With `T` the new values, `A` the cell areas, `C` and `e` the heat
+/// capacities and emissivities and `L` the linear transport operator, the
+/// residual of cell `i` is
+/// `C_i A_i (T_i - T_i^n) - dt (E_i - e_i A_i T_i^4 + (L T)_i)`
+/// where `E_i` collects the explicit terms.
+fn implicit_values(network: &grid::GridNetwork, eps1: f64, eps2: f64, light: &forcing::Illumination, dt: f64, implicit_advection: bool) -> Result<Vec<f64>,Error> {
+    let n = network.cells.len();
+    let areas: Vec<f64> = network.cells.iter().map(|c| c.polygon.area()).collect();
+    let old: Vec<f64> = network.cells.iter().map(|c| c.value).collect();
+    let capacity: Vec<f64> = network.cells.iter().map(|c| c.surface.heat_capacity).collect();
+    let emissivity: Vec<f64> = network.cells.iter().map(|c| c.surface.emissivity).collect();
+
+    let transport = transport_matrix(network,if implicit_advection { eps1 } else { 0.0 },eps2)?;
+    let mut explicit: Vec<f64> = network.cells.iter().map(|c| pgen::incident_flux(c,light)).collect();
+    if !implicit_advection {
+        let advection = transport_matrix(network,eps1,0.0)?.matvec(&old);
+        for (e, a) in explicit.iter_mut().zip(advection) {
+            *e += a;
+        }
+    }
+
+    let scale = sparse::norm(&(0..n).map(|i| capacity[i] * areas[i] * old[i] + dt * explicit[i]).collect::<Vec<f64>>());
+    let mut values = old.clone();
+    for iteration in 0..MAX_NEWTON_ITERATIONS {
+        let lt = transport.matvec(&values);
+        let residual: Vec<f64> = (0..n).map(|i| {
+            capacity[i] * areas[i] * (values[i] - old[i]) - dt * (explicit[i] - emissivity[i] * areas[i] * values[i].powi(4) + lt[i])
+        }).collect();
+        let residual_norm = sparse::norm(&residual);
+        debug!("Newton iteration {}: residual {}",iteration,residual_norm);
+        if residual_norm <= NEWTON_TOLERANCE * scale {
+            info!("Implicit step converged in {} Newton iterations",iteration);
+            if let Some(i) = values.iter().position(|&v| v < 0.0) {
+                return Err(Error::NegativeTemperature{cell: Some(i), value: values[i]});
+            }
+            return Ok(values);
+        }
+        let mut jacobian = SparseMatrix {
+            diag: transport.diag.iter().map(|d| -dt * d).collect(),
+            cols: transport.cols.clone(),
+            vals: transport.vals.iter().map(|row| row.iter().map(|v| -dt * v).collect()).collect()
+        };
+        for i in 0..n {
+            jacobian.diag[i] += areas[i] * (capacity[i] + 4.0 * dt * emissivity[i] * values[i].powi(3));
+        }
+        let rhs: Vec<f64> = residual.iter().map(|r| -r).collect();
+        let delta = jacobian.solve(&rhs,&vec![0.0; n],LINEAR_TOLERANCE,MAX_LINEAR_ITERATIONS)?;
+        for i in 0..n {
+            values[i] += delta[i];
+        }
+    }
+    Err(Error::SolverFailure{solver: "Newton iteration", reason: "did not converge"})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meshgen::MeshType;
+
+    #[test]
+    fn test_implicit_matches_explicit_for_small_dt() {
+        let mut network = pgen::init_mesh(MeshType::Geodesic(2), pgen::InitialCondition::Radiative).unwrap();
+        let tracer = network.add_field("tracer", network.cells.iter().map(|c| c.center().phi.sin()).collect()).unwrap();
+        let light = forcing::Illumination::default();
+        let dt = pgen::cfl_timestep(&network, pgen::COURANT_NUMBER, pgen::LengthScale::MeanCellSize, 1.0, 1.0).dt() / 100.0;
+        let explicit = step(&network, 1.0, 1.0, &light, dt, TimeScheme::ForwardEuler).unwrap();
+        let total = |n: &grid::GridNetwork| n.cells.iter().enumerate().map(|(i, c)| c.polygon.area() * n.value(tracer, i)).sum::<f64>();
+        for scheme in [TimeScheme::BackwardEuler, TimeScheme::Imex, TimeScheme::SspRk2, TimeScheme::SspRk3, TimeScheme::Rk4] {
+            let implicit = step(&network, 1.0, 1.0, &light, dt, scheme).unwrap();
+            for field in [grid::VALUE, tracer] {
+                let mut worst: f64 = 0.0;
+                let mut change: f64 = 0.0;
+                for i in 0..network.cells.len() {
+                    worst = worst.max((explicit.value(field, i) - implicit.value(field, i)).abs());
+                    change = change.max((explicit.value(field, i) - network.value(field, i)).abs());
+                }
+                // The schemes agree to O(dt^2) while the step itself is O(dt)
+                assert!(worst < 1e-2 * change, "{:?} disagrees on field {}", scheme, field);
+            }
+            // The tracer is only moved between cells
+            assert!((total(&implicit) - total(&network)).abs() < 1e-10);
+        }
+    }
+
+    #[test]
+    fn test_backward_euler_past_cfl_limit() {
+        let mut network = pgen::init_mesh(MeshType::Geodesic(2), pgen::InitialCondition::Constant(0.5)).unwrap();
+        let light = forcing::Illumination::default();
+        let dt = 0.5;
+        assert!(dt > 100.0 * pgen::cfl_timestep(&network, pgen::COURANT_NUMBER, pgen::LengthScale::MinCellSpacing, 1.0, 1.0).dt());
+        for _ in 0..40 {
+            network = step(&network, 1.0, 1.0, &light, dt, TimeScheme::BackwardEuler).unwrap();
+        }
+        assert!(network.min_value() >= 0.0);
+        assert!(matches!(pgen::check_energy_balance(&network,&light), pgen::EnergyBalance::Balanced(_)));
+    }
+
+    /// Integrate to a fixed time with `n_steps` steps of `scheme`
+    fn integrate(network: &grid::GridNetwork, t_end: f64, n_steps: usize, scheme: TimeScheme) -> Vec<f64> {
+        let dt = t_end / n_steps as f64;
+        let light = forcing::Illumination::default();
+        let mut network = with_values(network, values_of(network)).unwrap();
+        for _ in 0..n_steps {
+            network = step(&network, 1.0, 1.0, &light, dt, scheme).unwrap();
+        }
+        values_of(&network)
+    }
+
+    #[test]
+    fn test_convergence_order() {
+        let network = pgen::init_mesh(MeshType::Geodesic(1), pgen::InitialCondition::Constant(0.5)).unwrap();
+        let t_end = 10.0 * pgen::cfl_timestep(&network, pgen::COURANT_NUMBER, pgen::LengthScale::MinCellSpacing, 1.0, 1.0).dt();
+        let reference = integrate(&network, t_end, 320, TimeScheme::Rk4);
+        for scheme in [TimeScheme::ForwardEuler, TimeScheme::SspRk2, TimeScheme::SspRk3, TimeScheme::Rk4] {
+            let errors: Vec<f64> = [10, 20, 40].iter().map(|&n| {
+                let values = integrate(&network, t_end, n, scheme);
+                values.iter().zip(reference.iter()).map(|(a, b)| (a - b).abs()).fold(0.0, f64::max)
+            }).collect();
+            for pair in errors.windows(2) {
+                let order = (pair[0] / pair[1]).log2();
+                assert!((order - scheme.order() as f64).abs() < 0.3, "{:?} converged at order {}", scheme, order);
+            }
+        }
+    }
+}
@@ -0,0 +1,33 @@
+//! Energy-balance models on spherical meshes
+//!
+//! Meshes are built by `meshgen` from the spherical geometry in `coords`
+//! and `geometry`, and hold one value per cell in a `grid::GridNetwork`,
+//! along with the albedo, emissivity and heat capacity from `surface` and
+//! any further named fields.
+//! `pgen` sets up the problem and computes the fluxes under the stellar
+//! `forcing`, `integrate` and `steady` advance or solve it, and `driver`
+//! runs a whole simulation. `hysteresis` sweeps the stellar flux to trace
+//! the loops of the ice-albedo feedback.
+//! `render` draws the cell values as maps.
+
+pub mod error;
+pub mod coords;
+pub mod grid;
+pub mod surface;
+pub mod geometry;
+pub mod forcing;
+pub mod pgen;
+pub mod meshgen;
+pub mod meshio;
+pub mod netcdf;
+pub mod sparse;
+pub mod integrate;
+pub mod steady;
+pub mod driver;
+pub mod hysteresis;
+pub mod checkpoint;
+pub mod output;
+pub mod render;
+pub mod vtk;
+
+pub use error::Error;
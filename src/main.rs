@@ -1,28 +1,222 @@
-use std::f64::consts::PI;
+use std::collections::BTreeMap;
+use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
-use log::info;
+use clap::Parser;
+use log::{error, info};
 use simple_logger::{SimpleLogger};
 
+use isosphere::{checkpoint, driver, forcing, hysteresis, meshio, netcdf, output, pgen, render, vtk, Error};
 
-pub mod coords;
-pub mod grid;
-pub mod geometry;
-pub mod pgen;
-pub mod meshgen;
+mod cli;
+mod config;
+
+/// Time series a run is recorded in, besides the CSV snapshots
+struct Series {
+    pvd: Option<vtk::PvdWriter>,
+    netcdf: Option<netcdf::NetcdfWriter>,
+    /// Step of the last snapshot written
+    last: Option<usize>,
+}
+
+impl Series {
+    fn write(self: &mut Series, sim: &driver::Simulation) -> std::io::Result<()> {
+        if let Some(pvd) = self.pvd.as_mut() {
+            pvd.write_step(&sim.network, sim.time, sim.steps)?;
+        }
+        if let Some(nc) = self.netcdf.as_mut() {
+            nc.write_step(sim)?;
+        }
+        self.last = Some(sim.steps);
+        Ok(())
+    }
+}
+
+fn run(config: &config::RunConfig) -> Result<(), String> {
+    if let Some(sweep_config) = &config.sweep {
+        return sweep(config, sweep_config);
+    }
+    let mut sim = config.simulation().map_err(|e| e.to_string())?;
+    info!("Maximum value of the mesh is: {}", sim.network.max_value());
+    let output = &config.output;
+    let mesh_type = config.mesh.file.is_none().then(|| config.mesh.mesh_type());
+    // A restarted run continues the series of the run it was checkpointed from
+    let restart = config.restart.is_some();
+    let mut series = Series {
+        pvd: match &output.vtk {
+            Some(path) if restart => Some(vtk::PvdWriter::resume(path, sim.time).map_err(|e| format!("Could not continue {}: {}", path.display(), e))?),
+            Some(path) => Some(vtk::PvdWriter::new(path)),
+            None => None,
+        },
+        netcdf: match &output.netcdf {
+            Some(path) if restart => Some(netcdf::NetcdfWriter::resume(path, &sim, mesh_type).map_err(|e| format!("Could not continue {}: {}", path.display(), e))?),
+            Some(path) => Some(netcdf::NetcdfWriter::create(path, &sim, mesh_type).map_err(|e| format!("Could not create {}: {}", path.display(), e))?),
+            None => None,
+        },
+        last: None,
+    };
+    series.write(&sim).map_err(|e| format!("Could not write snapshot: {}", e))?;
+    // With a checkpoint to write, Ctrl-C stops the run after the current step instead of killing it
+    let interrupted = Arc::new(AtomicBool::new(false));
+    if output.checkpoint.is_some() {
+        let flag = interrupted.clone();
+        ctrlc::set_handler(move || flag.store(true, Ordering::SeqCst)).map_err(|e| format!("Could not install the Ctrl-C handler: {}", e))?;
+    }
+    let result = sim.run_with(&config.stop, |sim| {
+        _ = pgen::check_energy_balance(&sim.network, &sim.illumination()?);
+        info!("Maximum value of the mesh is: {}", sim.network.max_value());
+        info!("Average value of the mesh is: {}", sim.network.average_value());
+        info!("Minimum value of the mesh is: {}", sim.network.min_value());
+        if let Some(every) = output.every {
+            if every > 0 && sim.steps % every == 0 {
+                if let Some(path) = output.snapshot_path(sim.steps) {
+                    output::write_csv(&sim.network, &path).map_err(|e| {
+                        error!("Could not write {}", path.display());
+                        Error::Io(e)
+                    })?;
+                }
+                series.write(sim).map_err(|e| {
+                    error!("Could not write snapshot");
+                    Error::Io(e)
+                })?;
+            }
+        }
+        if let Some(path) = &output.checkpoint {
+            let due = output.checkpoint_every.is_some_and(|every| every > 0 && sim.steps % every == 0);
+            if due && !interrupted.load(Ordering::SeqCst) {
+                checkpoint::write_checkpoint(sim, path)?;
+                info!("Wrote checkpoint at step {} to {}", sim.steps, path.display());
+            }
+        }
+        if interrupted.load(Ordering::SeqCst) {
+            return Err(Error::Interrupted);
+        }
+        Ok(())
+    });
+    if let Some(path) = &output.checkpoint {
+        checkpoint::write_checkpoint(&sim, path).map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+        info!("Wrote checkpoint at step {} to {}", sim.steps, path.display());
+    }
+    result.map_err(|e| match e {
+        Error::Interrupted => format!("Interrupted at step {}", sim.steps),
+        e => e.to_string(),
+    })?;
+    if let Some(path) = &output.path {
+        output::write_csv(&sim.network, path).map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+        info!("Wrote cell values to {}", path.display());
+    }
+    let light = sim.illumination().map_err(|e| e.to_string())?;
+    if let Some(path) = &config.map.path {
+        let image = render::draw(&sim.network, &config.map.options(&light.substellar)).map_err(|e| e.to_string())?;
+        image.write(path).map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+        info!("Wrote a map of the cell values to {}", path.display());
+    }
+    if config.map.ascii {
+        print!("{}", render::ascii_map(&sim.network, 80).map_err(|e| e.to_string())?);
+    }
+    if series.last != Some(sim.steps) {
+        series.write(&sim).map_err(|e| format!("Could not write snapshot: {}", e))?;
+    }
+    if let (Some(pvd), Some(path)) = (&series.pvd, &output.vtk) {
+        info!("Wrote {} VTK snapshots to {}", pvd.len(), path.display());
+    }
+    if let (Some(nc), Some(path)) = (&series.netcdf, &output.netcdf) {
+        info!("Wrote {} NetCDF records to {}", nc.len(), path.display());
+    }
+    Ok(())
+}
+
+/// Sweep the stellar flux up and back down and report the hysteresis loop
+fn sweep(config: &config::RunConfig, sweep: &config::SweepConfig) -> Result<(), String> {
+    let mut sim = config.simulation().map_err(|e| e.to_string())?;
+    let points = hysteresis::sweep(&mut sim, &sweep.scales(), &config.stop).map_err(|e| e.to_string())?;
+    match &sweep.path {
+        Some(path) => {
+            hysteresis::write_csv(&points, path).map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+            info!("Wrote the hysteresis loop to {}", path.display());
+        },
+        None => {
+            println!("{:>12} {:>8} {:>12} {:>12} {:>12} {:>8}", "flux", "", "mean value", "ice cover", "albedo", "settled");
+            for p in points.iter() {
+                println!("{:>12.6} {:>8} {:>12.6} {:>12.6} {:>12.6} {:>8}", p.scale, if p.rising { "rising" } else { "falling" }, p.mean_value, p.ice_fraction, p.albedo, if p.settled() { "yes" } else { "no" });
+            }
+        },
+    }
+    if let Some(path) = &config.output.path {
+        output::write_csv(&sim.network, path).map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+        info!("Wrote cell values to {}", path.display());
+    }
+    Ok(())
+}
+
+fn mesh(args: &cli::MeshArgs, path: &std::path::Path) -> Result<(), String> {
+    let net = args.to_config().network(pgen::InitialCondition::Constant(0.0), &forcing::Illumination::default()).map_err(|e| e.to_string())?;
+    let written = match path.extension().and_then(|e| e.to_str()) {
+        Some("vtu") => vtk::write_vtu(&net, path),
+        Some("obj") => meshio::write_obj(&net, path),
+        Some("ply") => meshio::write_ply(&net, path),
+        Some("png") | Some("ppm") => {
+            let options = render::MapOptions { edges: true, ..Default::default() };
+            render::draw(&net, &options).map_err(std::io::Error::other).and_then(|image| image.write(path))
+        },
+        _ => output::write_csv(&net, path),
+    };
+    written.map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+    info!("Wrote {} cells to {}", net.cells.len(), path.display());
+    Ok(())
+}
+
+fn mesh_info(args: &cli::MeshArgs) -> Result<(), String> {
+    let net = args.to_config().network(pgen::InitialCondition::Constant(0.0), &forcing::Illumination::default()).map_err(|e| e.to_string())?;
+    let topology = net.topology();
+    let areas: Vec<f64> = net.cells.iter().map(|c| c.polygon.area()).collect();
+    let total: f64 = areas.iter().sum();
+    let max_area = areas.iter().cloned().fold(0.0, f64::max);
+    let min_area = areas.iter().cloned().fold(f64::INFINITY, f64::min);
+    let mut shapes: BTreeMap<usize, usize> = BTreeMap::new();
+    for face in topology.faces.iter() {
+        *shapes.entry(face.len()).or_insert(0) += 1;
+    }
+    match &args.mesh_file {
+        Some(path) => println!("Mesh:            {}", path.display()),
+        None => println!("Mesh:            {:?}", args.mesh_type()),
+    }
+    println!("Cells:           {}", net.cells.len());
+    println!("Vertices:        {}", topology.vertices.len());
+    println!("Edges:           {}", topology.edges.len());
+    for (sides, count) in shapes.iter() {
+        println!("  {}-sided cells: {}", sides, count);
+    }
+    println!("Total area:      {}", total);
+    println!("Cell area:       min {} / mean {} / max {}", min_area, total / areas.len() as f64, max_area);
+    println!("Area ratio:      {}", max_area / min_area);
+    println!("Min spacing:     {}", net.min_cell_spacing());
+    Ok(())
+}
 
 fn main() {
-    SimpleLogger::new().with_level(log::LevelFilter::Debug).init().unwrap();
-    let n = 3;
-    let e1 = 1.0;
-    let e2 = 1.0;
-    let mut net = pgen::init_mesh(n, pgen::InitialCondition::Constant(0.0));
-    print!("Maximum value of the mesh is: {}", net.max_value());
-    for i in 0..100 {
-        net = pgen::get_next_mesh(net, e1, e2);
-        _ = pgen::check_energy_balance(&net);
-        info!("Maximum value of the mesh is: {}", net.max_value());
-        info!("Average value of the mesh is: {}", net.average_value());
-        info!("Minimum value of the mesh is: {}", net.min_value());
-        
+    let args = cli::Cli::parse();
+    let config = match &args.command {
+        cli::Command::Run(run_args) => match run_args.to_config() {
+            Ok(config) => Some(config),
+            Err(e) => { eprintln!("{}", e); exit(1); }
+        },
+        _ => None
+    };
+    let level = match (args.log_level, &config) {
+        (Some(level), _) => level,
+        (None, Some(config)) => config.log_level().unwrap_or_else(|e| { eprintln!("{}", e); exit(1); }),
+        (None, None) => log::LevelFilter::Info
+    };
+    SimpleLogger::new().with_level(level).init().unwrap();
+    let result = match &args.command {
+        cli::Command::Run(_) => run(config.as_ref().expect("run always has a configuration")),
+        cli::Command::Mesh { mesh: mesh_args, output } => mesh(mesh_args, output),
+        cli::Command::Info { mesh: mesh_args } => mesh_info(mesh_args),
+    };
+    if let Err(e) = result {
+        error!("{}", e);
+        exit(1);
     }
 }
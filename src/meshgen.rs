@@ -1,25 +1,50 @@
-//! Generate a triangular mesh
+//! Generate meshes on the unit sphere
 //! 
 //! 
 
 use std::{f64::consts::PI};
 
-use crate::coords::subdivide_polygon;
+use crate::coords::{subdivide_polygon,subdivide_triangle};
 use log::{info,warn};
 
-use super::coords;
+use super::{coords,grid};
+use super::error::Error;
 
+/// The kinds of mesh that can be generated on the unit sphere
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum MeshType {
+    /// Icosahedron refined by splitting each cell about its centroid
+    Centroid(u32),
+    /// Icosahedron refined by splitting each triangle at its edge midpoints
+    Geodesic(u32),
+    /// Hexagonal/pentagonal dual of the geodesic icosphere
+    Goldberg(u32),
+    /// Regular latitude-longitude grid with `(n_lat, n_lon)` bands
+    LatLon(u32,u32),
+}
+
+impl MeshType {
+    /// Build the mesh; fails with `InvalidArgument` if the lat-lon grid has too few bands
+    pub fn generate(self:&MeshType) -> Result<Vec<coords::Polygon>,Error> {
+        match *self {
+            MeshType::Centroid(n) => icoshedron(n),
+            MeshType::Geodesic(n) => geodesic_icosphere(n),
+            MeshType::Goldberg(n) => goldberg(n),
+            MeshType::LatLon(n_lat,n_lon) => latlon(n_lat,n_lon),
+        }
+    }
+}
 
 /// From https://danielsieger.com/blog/2021/01/03/generating-platonic-solids.html
-pub fn icoshedron(n_subdivisions: u32) -> Vec<coords::Polygon> {
-    info!("Generating icoshedron with {} subdivisions",n_subdivisions);
-    let golden_ratio = (1. + (5. as f64).sqrt()) / 2.0;
+fn base_icosahedron() -> Vec<coords::Polygon> {
+    let golden_ratio = (1. + 5.0_f64.sqrt()) / 2.0;
     let a = 1.0;
     let b = 1.0/golden_ratio;
     let mag = (a*a + b*b).sqrt();
     let a = a/mag;
     let b = b/mag;
 
+    // The vertices are unit vectors by construction, so these cannot fail
     let v1 = coords::Coordinate::from_cart(0.0,b,-a).unwrap();
     let v2 = coords::Coordinate::from_cart(b,a,0.0).unwrap();
     let v3 = coords::Coordinate::from_cart(-b,a,0.0).unwrap();
@@ -33,30 +58,35 @@ pub fn icoshedron(n_subdivisions: u32) -> Vec<coords::Polygon> {
     let v11 = coords::Coordinate::from_cart(b,-a,0.0).unwrap();
     let v12 = coords::Coordinate::from_cart(-b,-a,0.0).unwrap();
 
-    let cells = vec![
-        coords::Polygon::new(vec![v3.clone(),v2.clone(),v1.clone()]),
-        coords::Polygon::new(vec![v2.clone(),v3.clone(),v4.clone()]),
-        coords::Polygon::new(vec![v6.clone(),v5.clone(),v4.clone()]),
-        coords::Polygon::new(vec![v5.clone(),v9.clone(),v4.clone()]),
-        coords::Polygon::new(vec![v8.clone(),v7.clone(),v1.clone()]),
-        coords::Polygon::new(vec![v7.clone(),v10.clone(),v1.clone()]),
-        coords::Polygon::new(vec![v12.clone(),v11.clone(),v5.clone()]),
-        coords::Polygon::new(vec![v11.clone(),v12.clone(),v7.clone()]),
-        coords::Polygon::new(vec![v10.clone(),v6.clone(),v3.clone()]),
-        coords::Polygon::new(vec![v6.clone(),v10.clone(),v12.clone()]),
-        coords::Polygon::new(vec![v9.clone(),v8.clone(),v2.clone()]),
-        coords::Polygon::new(vec![v8.clone(),v9.clone(),v11.clone()]),
-        coords::Polygon::new(vec![v3.clone(),v6.clone(),v4.clone()]),
-        coords::Polygon::new(vec![v9.clone(),v2.clone(),v4.clone()]),
-        coords::Polygon::new(vec![v10.clone(),v3.clone(),v1.clone()]),
-        coords::Polygon::new(vec![v2.clone(),v8.clone(),v1.clone()]),
-        coords::Polygon::new(vec![v12.clone(),v10.clone(),v7.clone()]),
-        coords::Polygon::new(vec![v8.clone(),v11.clone(),v7.clone()]),
-        coords::Polygon::new(vec![v6.clone(),v12.clone(),v5.clone()]),
-        coords::Polygon::new(vec![v11.clone(),v9.clone(),v5.clone()]),
-    ];
+    vec![
+        coords::Polygon::new(vec![v3,v2,v1]),
+        coords::Polygon::new(vec![v2,v3,v4]),
+        coords::Polygon::new(vec![v6,v5,v4]),
+        coords::Polygon::new(vec![v5,v9,v4]),
+        coords::Polygon::new(vec![v8,v7,v1]),
+        coords::Polygon::new(vec![v7,v10,v1]),
+        coords::Polygon::new(vec![v12,v11,v5]),
+        coords::Polygon::new(vec![v11,v12,v7]),
+        coords::Polygon::new(vec![v10,v6,v3]),
+        coords::Polygon::new(vec![v6,v10,v12]),
+        coords::Polygon::new(vec![v9,v8,v2]),
+        coords::Polygon::new(vec![v8,v9,v11]),
+        coords::Polygon::new(vec![v3,v6,v4]),
+        coords::Polygon::new(vec![v9,v2,v4]),
+        coords::Polygon::new(vec![v10,v3,v1]),
+        coords::Polygon::new(vec![v2,v8,v1]),
+        coords::Polygon::new(vec![v12,v10,v7]),
+        coords::Polygon::new(vec![v8,v11,v7]),
+        coords::Polygon::new(vec![v6,v12,v5]),
+        coords::Polygon::new(vec![v11,v9,v5]),
+    ]
+}
+
+pub fn icoshedron(n_subdivisions: u32) -> Result<Vec<coords::Polygon>,Error> {
+    info!("Generating icoshedron with {} subdivisions",n_subdivisions);
+    let cells = base_icosahedron();
     if n_subdivisions == 0 {
-        cells
+        Ok(cells)
     }
     else {
         let mut cells = cells;
@@ -66,7 +96,7 @@ pub fn icoshedron(n_subdivisions: u32) -> Vec<coords::Polygon> {
             info!("Starting subdivision {}",i);
             let mut new_cells:Vec<coords::Polygon> = Vec::new();
             for cell in cells.iter() {
-                let subdivisions = subdivide_polygon(cell.clone());
+                let subdivisions = subdivide_polygon(cell.clone())?;
                 for s in subdivisions.iter() {
                     let area = s.area();
                     if 1.0 - area / expected_area > 0.01 {
@@ -81,7 +111,175 @@ pub fn icoshedron(n_subdivisions: u32) -> Vec<coords::Polygon> {
             cells = new_cells;
             info!("There are now {} cells",cells.len());
         }
-        cells
+        Ok(cells)
     }
 
-}
\ No newline at end of file
+}
+
+/// Geodesic (Class I) icosphere.
+///
+/// Each level splits every triangle into four at its edge midpoints,
+/// so cells stay close to equilateral and close to equal-area.
+pub fn geodesic_icosphere(n_subdivisions: u32) -> Result<Vec<coords::Polygon>,Error> {
+    info!("Generating geodesic icosphere with {} subdivisions",n_subdivisions);
+    let mut cells = base_icosahedron();
+    for i in 0..n_subdivisions {
+        info!("Starting subdivision {}",i);
+        let mut new_cells:Vec<coords::Polygon> = Vec::with_capacity(cells.len() * 4);
+        for cell in cells.into_iter() {
+            new_cells.extend(subdivide_triangle(cell)?);
+        }
+        cells = new_cells;
+        info!("There are now {} cells",cells.len());
+    }
+    let areas:Vec<f64> = cells.iter().map(|c| c.area()).collect();
+    let max_area = areas.iter().cloned().fold(0.0,f64::max);
+    let min_area = areas.iter().cloned().fold(f64::INFINITY,f64::min);
+    info!("Cell area ratio (max/min) is {}",max_area / min_area);
+    Ok(cells)
+}
+
+/// Build the dual of a mesh.
+///
+/// Each vertex of `polygons` becomes a cell whose nodes are the centers
+/// of the polygons around it, ordered counter-clockwise seen from outside.
+pub fn dual_mesh(polygons: &[coords::Polygon]) -> Result<Vec<coords::Polygon>,Error> {
+    let topology = grid::MeshTopology::from_polygons(polygons);
+    let centers = polygons.iter().map(|p| p.center()).collect::<Result<Vec<coords::Coordinate>,Error>>()?;
+    let mut cells:Vec<coords::Polygon> = Vec::with_capacity(topology.vertices.len());
+    for (v, vertex) in topology.vertices.iter().enumerate() {
+        let (vx,vy,vz) = vertex.cart()?;
+        // Tangent basis at the vertex: e1 is any direction perpendicular to it, e2 = v x e1
+        let (ax,ay,az) = if vz.abs() < 0.9 { (0.0,0.0,1.0) } else { (1.0,0.0,0.0) };
+        let d = ax*vx + ay*vy + az*vz;
+        let (e1x,e1y,e1z) = (ax - d*vx, ay - d*vy, az - d*vz);
+        let (e2x,e2y,e2z) = (vy*e1z - vz*e1y, vz*e1x - vx*e1z, vx*e1y - vy*e1x);
+        let mut around:Vec<(f64,usize)> = topology.vertex_cells[v].iter().map(|&c| {
+            let (cx,cy,cz) = centers[c].cart()?;
+            let angle = (cx*e2x + cy*e2y + cz*e2z).atan2(cx*e1x + cy*e1y + cz*e1z);
+            Ok((angle, c))
+        }).collect::<Result<_,Error>>()?;
+        around.sort_by(|a, b| a.0.total_cmp(&b.0));
+        cells.push(coords::Polygon::new(around.iter().map(|&(_, c)| centers[c]).collect()));
+    }
+    Ok(cells)
+}
+
+/// Goldberg polyhedron: 12 pentagons and the rest hexagons.
+///
+/// Built as the dual of `geodesic_icosphere(n_subdivisions)`.
+pub fn goldberg(n_subdivisions: u32) -> Result<Vec<coords::Polygon>,Error> {
+    let triangles = geodesic_icosphere(n_subdivisions)?;
+    info!("Generating dual mesh of {} triangles",triangles.len());
+    let cells = dual_mesh(&triangles)?;
+    info!("There are now {} cells",cells.len());
+    Ok(cells)
+}
+
+/// Regular latitude-longitude grid.
+///
+/// The sphere is cut into `n_lat` bands of equal colatitude and `n_lon` bands
+/// of equal longitude. Cells are quadrilaterals, except in the two bands next
+/// to the poles where they are triangles sharing the pole as a vertex.
+/// It needs at least two latitude and three longitude bands.
+pub fn latlon(n_lat: u32, n_lon: u32) -> Result<Vec<coords::Polygon>,Error> {
+    info!("Generating latitude-longitude grid with {} x {} bands",n_lat,n_lon);
+    if n_lat < 2 {
+        return Err(Error::InvalidArgument("A latitude-longitude grid needs at least two latitude bands"));
+    }
+    if n_lon < 3 {
+        return Err(Error::InvalidArgument("A latitude-longitude grid needs at least three longitude bands"));
+    }
+    let n_pole = coords::Coordinate::new(0.0,0.0)?;
+    let s_pole = coords::Coordinate::new(0.0,PI)?;
+    // Interior rings of nodes, from north to south. Each ring is built once
+    // so that neighbouring cells share bit-identical vertices.
+    let rings:Vec<Vec<coords::Coordinate>> = (1..n_lat).map(|i| {
+        let theta = PI * i as f64 / n_lat as f64;
+        (0..n_lon).map(|j| {
+            let phi = 2.0 * PI * j as f64 / n_lon as f64 - PI;
+            coords::Coordinate::new(phi,theta)
+        }).collect()
+    }).collect::<Result<_,Error>>()?;
+    let n_lon = n_lon as usize;
+    let mut cells:Vec<coords::Polygon> = Vec::with_capacity(rings.len() * n_lon + n_lon);
+    for j in 0..n_lon {
+        let k = (j + 1) % n_lon;
+        cells.push(coords::Polygon::new(vec![n_pole,rings[0][j],rings[0][k]]));
+    }
+    for band in rings.windows(2) {
+        for j in 0..n_lon {
+            let k = (j + 1) % n_lon;
+            cells.push(coords::Polygon::new(vec![band[0][j],band[1][j],band[1][k],band[0][k]]));
+        }
+    }
+    let last = &rings[rings.len() - 1];
+    for j in 0..n_lon {
+        let k = (j + 1) % n_lon;
+        cells.push(coords::Polygon::new(vec![last[j],s_pole,last[k]]));
+    }
+    info!("There are now {} cells",cells.len());
+    Ok(cells)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::MeshTopology;
+
+    #[test]
+    fn test_geodesic_icosphere() {
+        let cells = geodesic_icosphere(3).unwrap();
+        assert_eq!(cells.len(), 20 * 4_usize.pow(3));
+        let total: f64 = cells.iter().map(|c| c.area()).sum();
+        assert!((total - 4.0 * PI).abs() < 1e-9);
+        let areas: Vec<f64> = cells.iter().map(|c| c.area()).collect();
+        let max_area = areas.iter().cloned().fold(0.0, f64::max);
+        let min_area = areas.iter().cloned().fold(f64::INFINITY, f64::min);
+        assert!(max_area / min_area < 1.5);
+
+        let topology = MeshTopology::from_polygons(&cells);
+        assert_eq!(topology.vertices.len(), 10 * 4_usize.pow(3) + 2);
+        for edge in topology.edges.iter() {
+            assert_eq!(edge.cells.len(), 2);
+        }
+    }
+
+    #[test]
+    fn test_goldberg() {
+        let n = 2;
+        let cells = goldberg(n).unwrap();
+        assert_eq!(cells.len(), 10 * 4_usize.pow(n) + 2);
+        assert_eq!(cells.iter().filter(|c| c.nodes.len() == 5).count(), 12);
+        assert_eq!(cells.iter().filter(|c| c.nodes.len() == 6).count(), cells.len() - 12);
+        let total: f64 = cells.iter().map(|c| c.area()).sum();
+        assert!((total - 4.0 * PI).abs() < 1e-9);
+
+        let topology = MeshTopology::from_polygons(&cells);
+        assert_eq!(topology.vertices.len(), 20 * 4_usize.pow(n));
+        for edge in topology.edges.iter() {
+            assert_eq!(edge.cells.len(), 2);
+        }
+    }
+
+    #[test]
+    fn test_latlon() {
+        let (n_lat, n_lon) = (9, 12);
+        let cells = latlon(n_lat, n_lon).unwrap();
+        assert_eq!(cells.len(), (n_lat * n_lon) as usize);
+        assert_eq!(cells.iter().filter(|c| c.nodes.len() == 3).count(), 2 * n_lon as usize);
+        let total: f64 = cells.iter().map(|c| c.area()).sum();
+        assert!((total - 4.0 * PI).abs() < 1e-9);
+        for cell in cells.iter() {
+            assert!(cell.area() > 0.0);
+        }
+
+        let topology = MeshTopology::from_polygons(&cells);
+        assert_eq!(topology.vertices.len(), ((n_lat - 1) * n_lon + 2) as usize);
+        for edge in topology.edges.iter() {
+            assert_eq!(edge.cells.len(), 2);
+        }
+        assert!(matches!(MeshType::LatLon(1, 12).generate(), Err(Error::InvalidArgument(_))));
+        assert!(matches!(MeshType::LatLon(9, 2).generate(), Err(Error::InvalidArgument(_))));
+    }
+}
@@ -0,0 +1,269 @@
+//! Wavefront OBJ and PLY meshes
+//!
+//! Writers store the shared vertices and one face per cell, with the cell
+//! values attached to the faces. Readers accept any polygon mesh and project
+//! its vertices onto the unit sphere.
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use super::coords;
+use super::error::Error;
+use super::grid;
+
+/// Cell values scaled to `[0, 1]` over the range of the mesh
+fn normalized_values(network: &grid::GridNetwork) -> Vec<f64> {
+    let (min, max) = (network.min_value(), network.max_value());
+    let range = if max > min { max - min } else { 1.0 };
+    network.cells.iter().map(|c| (c.value - min) / range).collect()
+}
+
+/// Blue to white to red ramp for `t` in `[0, 1]`
+fn ramp(t: f64) -> (u8, u8, u8) {
+    let channel = |x: f64| (255.0 * x.clamp(0.0, 1.0)).round() as u8;
+    if t < 0.5 {
+        (channel(2.0 * t), channel(2.0 * t), 255)
+    }
+    else {
+        (255, channel(2.0 - 2.0 * t), channel(2.0 - 2.0 * t))
+    }
+}
+
+/// Write `network` as an OBJ file.
+///
+/// OBJ has no per-face attributes, so each face gets its own texture
+/// coordinate whose `u` is the cell value scaled to `[0, 1]` over the mesh.
+/// Any renderer can then colour the cells with a 1D colormap texture.
+pub fn write_obj(network: &grid::GridNetwork, path: &Path) -> std::io::Result<()> {
+    let topology = network.topology();
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "# {} cells, value range [{}, {}]", network.cells.len(), network.min_value(), network.max_value())?;
+    for vertex in topology.vertices.iter() {
+        let (x, y, z) = vertex.cart().map_err(std::io::Error::other)?;
+        writeln!(out, "v {} {} {}", x, y, z)?;
+    }
+    for t in normalized_values(network) {
+        writeln!(out, "vt {} 0", t)?;
+    }
+    for (i, face) in topology.faces.iter().enumerate() {
+        let refs: Vec<String> = face.iter().map(|v| format!("{}/{}", v + 1, i + 1)).collect();
+        writeln!(out, "f {}", refs.join(" "))?;
+    }
+    out.flush()
+}
+
+/// Write `network` as an ASCII PLY file with the value and a colour on every face
+pub fn write_ply(network: &grid::GridNetwork, path: &Path) -> std::io::Result<()> {
+    let topology = network.topology();
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "ply")?;
+    writeln!(out, "format ascii 1.0")?;
+    writeln!(out, "element vertex {}", topology.vertices.len())?;
+    writeln!(out, "property double x")?;
+    writeln!(out, "property double y")?;
+    writeln!(out, "property double z")?;
+    writeln!(out, "element face {}", topology.faces.len())?;
+    writeln!(out, "property list uchar int vertex_indices")?;
+    writeln!(out, "property double value")?;
+    writeln!(out, "property uchar red")?;
+    writeln!(out, "property uchar green")?;
+    writeln!(out, "property uchar blue")?;
+    writeln!(out, "end_header")?;
+    for vertex in topology.vertices.iter() {
+        let (x, y, z) = vertex.cart().map_err(std::io::Error::other)?;
+        writeln!(out, "{} {} {}", x, y, z)?;
+    }
+    for ((face, cell), t) in topology.faces.iter().zip(network.cells.iter()).zip(normalized_values(network)) {
+        let ids: Vec<String> = face.iter().map(|v| v.to_string()).collect();
+        let (r, g, b) = ramp(t);
+        writeln!(out, "{} {} {} {} {} {}", face.len(), ids.join(" "), cell.value, r, g, b)?;
+    }
+    out.flush()
+}
+
+/// Project a vertex read from a file onto the unit sphere
+fn project(x: f64, y: f64, z: f64) -> Result<coords::Coordinate, Error> {
+    let mag = (x*x + y*y + z*z).sqrt();
+    coords::Coordinate::from_cart(x/mag, y/mag, z/mag)
+}
+
+fn parse_error(line: usize, reason: &'static str) -> Error {
+    Error::Parse { line, reason }
+}
+
+/// Build a polygon from 0-based vertex indices
+fn polygon(vertices: &[coords::Coordinate], ids: &[usize], line: usize) -> Result<coords::Polygon, Error> {
+    if ids.len() < 3 {
+        return Err(parse_error(line, "a face needs at least three vertices"));
+    }
+    let nodes = ids.iter().map(|&v| vertices.get(v).copied().ok_or(parse_error(line, "vertex index out of range"))).collect::<Result<_, _>>()?;
+    Ok(coords::Polygon::new(nodes))
+}
+
+/// Read the faces of an OBJ file as polygons on the unit sphere.
+///
+/// Only `v` and `f` statements are used; faces may refer to vertices with
+/// positive or negative (relative) indices.
+pub fn read_obj(path: &Path) -> Result<Vec<coords::Polygon>, Error> {
+    let text = fs::read_to_string(path)?;
+    let mut vertices: Vec<coords::Coordinate> = Vec::new();
+    let mut faces: Vec<(usize, Vec<usize>)> = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line_no = n + 1;
+        let mut tokens = line.split_whitespace();
+        match tokens.next() {
+            Some("v") => {
+                let xyz: Vec<f64> = tokens.take(3).map(|t| t.parse().map_err(|_| parse_error(line_no, "invalid vertex coordinate"))).collect::<Result<_, _>>()?;
+                if xyz.len() != 3 {
+                    return Err(parse_error(line_no, "a vertex needs three coordinates"));
+                }
+                vertices.push(project(xyz[0], xyz[1], xyz[2])?);
+            },
+            Some("f") => {
+                let mut ids = Vec::new();
+                for token in tokens {
+                    let index: i64 = token.split('/').next().unwrap_or("").parse().map_err(|_| parse_error(line_no, "invalid face index"))?;
+                    let id = match index {
+                        i if i > 0 => i - 1,
+                        i if i < 0 => vertices.len() as i64 + i,
+                        _ => return Err(parse_error(line_no, "face indices start at 1")),
+                    };
+                    ids.push(usize::try_from(id).map_err(|_| parse_error(line_no, "vertex index out of range"))?);
+                }
+                faces.push((line_no, ids));
+            },
+            _ => {}
+        }
+    }
+    faces.iter().map(|(line_no, ids)| polygon(&vertices, ids, *line_no)).collect()
+}
+
+/// An element declared in a PLY header, with its properties as `(name, is_list)`
+struct PlyElement {
+    name: String,
+    count: usize,
+    properties: Vec<(String, bool)>,
+}
+
+/// Read the faces of an ASCII PLY file as polygons on the unit sphere
+pub fn read_ply(path: &Path) -> Result<Vec<coords::Polygon>, Error> {
+    let text = fs::read_to_string(path)?;
+    let mut lines = text.lines().enumerate().map(|(n, l)| (n + 1, l));
+    if lines.next().map(|(_, l)| l.trim()) != Some("ply") {
+        return Err(parse_error(1, "not a PLY file"));
+    }
+    let mut elements: Vec<PlyElement> = Vec::new();
+    loop {
+        let (line_no, line) = lines.next().ok_or(parse_error(0, "missing end_header"))?;
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        match tokens.as_slice() {
+            ["end_header"] => break,
+            ["format", "ascii", _] => {},
+            ["format", ..] => return Err(parse_error(line_no, "only ASCII PLY files are supported")),
+            ["element", name, count] => {
+                let count = count.parse().map_err(|_| parse_error(line_no, "invalid element count"))?;
+                elements.push(PlyElement { name: name.to_string(), count, properties: Vec::new() });
+            },
+            ["property", "list", _, _, name] => {
+                let element = elements.last_mut().ok_or(parse_error(line_no, "property before any element"))?;
+                element.properties.push((name.to_string(), true));
+            },
+            ["property", _, name] => {
+                let element = elements.last_mut().ok_or(parse_error(line_no, "property before any element"))?;
+                element.properties.push((name.to_string(), false));
+            },
+            _ => {}
+        }
+    }
+
+    let mut vertices: Vec<coords::Coordinate> = Vec::new();
+    let mut polygons: Vec<coords::Polygon> = Vec::new();
+    for element in elements.iter() {
+        for _ in 0..element.count {
+            let (line_no, line) = lines.next().ok_or(parse_error(0, "file ends before all elements are read"))?;
+            let mut tokens = line.split_whitespace();
+            let mut next = || tokens.next().ok_or(parse_error(line_no, "invalid number"));
+            let number = |t: &str| t.parse::<f64>().map_err(|_| parse_error(line_no, "invalid number"));
+            let index = |t: &str| t.parse::<usize>().map_err(|_| parse_error(line_no, "list lengths and vertex indices must be non-negative integers"));
+            let mut xyz = [0.0; 3];
+            let mut ids: Vec<usize> = Vec::new();
+            for (property, is_list) in element.properties.iter() {
+                if *is_list {
+                    let n = index(next()?)?;
+                    let list = (0..n).map(|_| next()).collect::<Result<Vec<&str>, _>>()?;
+                    if property == "vertex_indices" || property == "vertex_index" {
+                        ids = list.into_iter().map(index).collect::<Result<_, _>>()?;
+                    }
+                    else {
+                        for t in list { number(t)?; }
+                    }
+                    continue;
+                }
+                let value = number(next()?)?;
+                match property.as_str() {
+                    "x" => xyz[0] = value,
+                    "y" => xyz[1] = value,
+                    "z" => xyz[2] = value,
+                    _ => {}
+                }
+            }
+            match element.name.as_str() {
+                "vertex" => vertices.push(project(xyz[0], xyz[1], xyz[2])?),
+                "face" => polygons.push(polygon(&vertices, &ids, line_no)?),
+                _ => {}
+            }
+        }
+    }
+    Ok(polygons)
+}
+
+/// Read an `.obj` or `.ply` mesh, chosen by extension
+pub fn read_mesh(path: &Path) -> Result<Vec<coords::Polygon>, Error> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("obj") => read_obj(path),
+        Some("ply") => read_ply(path),
+        _ => Err(Error::InvalidArgument("Unknown mesh format; expected .obj or .ply")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meshgen::MeshType;
+    use crate::pgen;
+
+    #[test]
+    fn test_round_trip() {
+        let dir = std::env::temp_dir().join(format!("isosphere_meshio_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let network = pgen::init_mesh(MeshType::Goldberg(1), pgen::InitialCondition::Radiative).unwrap();
+        for name in ["mesh.obj", "mesh.ply"] {
+            let path = dir.join(name);
+            match name {
+                "mesh.obj" => write_obj(&network, &path).unwrap(),
+                _ => write_ply(&network, &path).unwrap(),
+            }
+            let polygons = read_mesh(&path).unwrap();
+            assert_eq!(polygons.len(), network.cells.len());
+            for (p, cell) in polygons.iter().zip(network.cells.iter()) {
+                assert_eq!(p.nodes.len(), cell.polygon.nodes.len());
+                assert!(p.center().unwrap().angle_between(&cell.center()) < 1e-12);
+            }
+            // Shared vertices are read back as the same coordinates
+            let read = grid::GridNetwork::new(polygons.into_iter().map(|p| grid::GridCell::new(p, 0.0).unwrap()).collect());
+            assert_eq!(read.topology().vertices.len(), network.topology().vertices.len());
+            assert_eq!(read.topology().neighbors, network.topology().neighbors);
+        }
+        // Negative or fractional vertex indices are rejected, not truncated
+        let header = "ply\nformat ascii 1.0\nelement vertex 3\nproperty float x\nproperty float y\nproperty float z\nelement face 1\nproperty list uchar int vertex_indices\nend_header\n1 0 0\n0 1 0\n0 0 1\n";
+        let path = dir.join("bad.ply");
+        for face in ["3 -1 1.9 2", "3 0 1.9 2", "3.0 0 1 2"] {
+            fs::write(&path, format!("{}{}\n", header, face)).unwrap();
+            assert!(matches!(read_mesh(&path), Err(Error::Parse{line: 13, ..})));
+        }
+        fs::write(&path, format!("{}3 0 1 2\n", header)).unwrap();
+        assert_eq!(read_mesh(&path).unwrap().len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,454 @@
+//! NetCDF output following the CF and UGRID conventions
+//!
+//! Files are written in the classic 64-bit offset format (CDF-2) by a small
+//! pure-Rust encoder, so no NetCDF library is needed. The mesh is stored once
+//! as a UGRID `mesh_topology`, and each call to `NetcdfWriter::write_step`
+//! appends one record along the unlimited `time` dimension holding the cell
+//! values, the timestep and the stability limit it came from.
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::{driver, meshgen, pgen};
+
+static NC_DIMENSION: u32 = 0x0A;
+static NC_VARIABLE: u32 = 0x0B;
+static NC_ATTRIBUTE: u32 = 0x0C;
+static NC_CHAR: u32 = 2;
+static NC_INT: u32 = 4;
+static NC_DOUBLE: u32 = 6;
+
+/// Fill value of the face-node connectivity for faces with fewer corners than the largest
+static FILL_INDEX: i32 = -1;
+
+enum Attribute {
+    Text(String),
+    Int(i32),
+    Ints(Vec<i32>),
+    Double(f64),
+}
+
+fn text(s: &str) -> Attribute {
+    Attribute::Text(String::from(s))
+}
+
+enum Values {
+    Int(Vec<i32>),
+    Double(Vec<f64>),
+}
+
+impl Values {
+    fn nc_type(self: &Values) -> u32 {
+        match self {
+            Values::Int(_) => NC_INT,
+            Values::Double(_) => NC_DOUBLE,
+        }
+    }
+    fn encode(self: &Values, out: &mut Vec<u8>) {
+        match self {
+            Values::Int(v) => v.iter().for_each(|x| out.extend_from_slice(&x.to_be_bytes())),
+            Values::Double(v) => v.iter().for_each(|x| out.extend_from_slice(&x.to_be_bytes())),
+        }
+    }
+}
+
+struct Variable {
+    name: String,
+    dims: Vec<usize>,
+    attributes: Vec<(&'static str, Attribute)>,
+    nc_type: u32,
+    /// Bytes per record for record variables, otherwise in total
+    size: usize,
+    /// Data of a fixed-size variable; `None` for record variables
+    data: Option<Values>,
+}
+
+fn fixed(name: &str, dims: Vec<usize>, attributes: Vec<(&'static str, Attribute)>, data: Values) -> Variable {
+    let mut bytes = Vec::new();
+    data.encode(&mut bytes);
+    Variable { name: name.to_string(), dims, attributes, nc_type: data.nc_type(), size: bytes.len(), data: Some(data) }
+}
+
+fn record(name: &str, dims: Vec<usize>, attributes: Vec<(&'static str, Attribute)>, nc_type: u32, size: usize) -> Variable {
+    Variable { name: name.to_string(), dims, attributes, nc_type, size, data: None }
+}
+
+fn pad(out: &mut Vec<u8>) {
+    while !out.len().is_multiple_of(4) {
+        out.push(0);
+    }
+}
+
+fn put_u32(out: &mut Vec<u8>, x: u32) {
+    out.extend_from_slice(&x.to_be_bytes());
+}
+
+fn put_name(out: &mut Vec<u8>, name: &str) {
+    put_u32(out, name.len() as u32);
+    out.extend_from_slice(name.as_bytes());
+    pad(out);
+}
+
+fn put_attributes(out: &mut Vec<u8>, attributes: &[(&str, Attribute)]) {
+    if attributes.is_empty() {
+        put_u32(out, 0);
+        put_u32(out, 0);
+        return;
+    }
+    put_u32(out, NC_ATTRIBUTE);
+    put_u32(out, attributes.len() as u32);
+    for (name, value) in attributes.iter() {
+        put_name(out, name);
+        match value {
+            Attribute::Text(s) => {
+                put_u32(out, NC_CHAR);
+                put_u32(out, s.len() as u32);
+                out.extend_from_slice(s.as_bytes());
+            },
+            Attribute::Int(x) => {
+                put_u32(out, NC_INT);
+                put_u32(out, 1);
+                out.extend_from_slice(&x.to_be_bytes());
+            },
+            Attribute::Ints(v) => {
+                put_u32(out, NC_INT);
+                put_u32(out, v.len() as u32);
+                v.iter().for_each(|x| out.extend_from_slice(&x.to_be_bytes()));
+            },
+            Attribute::Double(x) => {
+                put_u32(out, NC_DOUBLE);
+                put_u32(out, 1);
+                out.extend_from_slice(&x.to_be_bytes());
+            },
+        }
+        pad(out);
+    }
+}
+
+/// Code stored for each `CFL_Limiter`, matching the `flag_meanings` of `cfl_limiter`
+fn limiter_code(limiter: &Option<pgen::CFL_Limiter>) -> i32 {
+    match limiter {
+        None => 0,
+        Some(pgen::CFL_Limiter::AdvectionLimited(_)) => 1,
+        Some(pgen::CFL_Limiter::DiffusionLimited(_)) => 2,
+        Some(pgen::CFL_Limiter::SourceLimited(_)) => 3,
+        Some(pgen::CFL_Limiter::NoLimit(_)) => 4,
+    }
+}
+
+/// Appends snapshots of a running simulation to a NetCDF file
+pub struct NetcdfWriter {
+    out: BufWriter<File>,
+    n_cells: usize,
+    n_fields: usize,
+    records: u32,
+}
+
+/// Header and fixed-size data of the file for `sim`, and the size of one record
+fn encode(sim: &driver::Simulation, mesh: Option<meshgen::MeshType>) -> (Vec<u8>, u64) {
+    let network = &sim.network;
+    let topology = network.topology();
+    let n_nodes = topology.vertices.len();
+    let n_cells = network.cells.len();
+    let max_corners = topology.faces.iter().map(|f| f.len()).max().unwrap_or(0);
+
+    // Dimension ids, in the order they are declared
+    let (time, node, face, corner) = (0, 1, 2, 3);
+    let dimensions = [("time", 0), ("nMesh2_node", n_nodes), ("nMesh2_face", n_cells), ("nMaxMesh2_face_nodes", max_corners)];
+
+    let lonlat = |c: &crate::coords::Coordinate| (c.phi.to_degrees(), 90.0 - c.theta.to_degrees());
+    let nodes: Vec<(f64, f64)> = topology.vertices.iter().map(lonlat).collect();
+    let centers: Vec<(f64, f64)> = network.cells.iter().map(|c| lonlat(&c.center())).collect();
+    let mut connectivity = Vec::with_capacity(n_cells * max_corners);
+    for face in topology.faces.iter() {
+        connectivity.extend(face.iter().map(|&v| v as i32));
+        connectivity.extend(std::iter::repeat_n(FILL_INDEX, max_corners - face.len()));
+    }
+
+    let mut variables = vec![
+        fixed("Mesh2", vec![], vec![
+            ("cf_role", text("mesh_topology")),
+            ("long_name", text("Topology of the spherical mesh")),
+            ("topology_dimension", Attribute::Int(2)),
+            ("node_coordinates", text("Mesh2_node_x Mesh2_node_y")),
+            ("face_node_connectivity", text("Mesh2_face_nodes")),
+            ("face_dimension", text("nMesh2_face")),
+            ("face_coordinates", text("Mesh2_face_x Mesh2_face_y")),
+        ], Values::Int(vec![0])),
+        fixed("Mesh2_node_x", vec![node], vec![
+            ("standard_name", text("longitude")),
+            ("units", text("degrees_east")),
+        ], Values::Double(nodes.iter().map(|n| n.0).collect())),
+        fixed("Mesh2_node_y", vec![node], vec![
+            ("standard_name", text("latitude")),
+            ("units", text("degrees_north")),
+        ], Values::Double(nodes.iter().map(|n| n.1).collect())),
+        fixed("Mesh2_face_nodes", vec![face, corner], vec![
+            ("cf_role", text("face_node_connectivity")),
+            ("long_name", text("Vertices of each cell, in polygon order")),
+            ("start_index", Attribute::Int(0)),
+            ("_FillValue", Attribute::Int(FILL_INDEX)),
+        ], Values::Int(connectivity)),
+        fixed("Mesh2_face_x", vec![face], vec![
+            ("standard_name", text("longitude")),
+            ("long_name", text("Longitude of the cell centroid")),
+            ("units", text("degrees_east")),
+        ], Values::Double(centers.iter().map(|c| c.0).collect())),
+        fixed("Mesh2_face_y", vec![face], vec![
+            ("standard_name", text("latitude")),
+            ("long_name", text("Latitude of the cell centroid")),
+            ("units", text("degrees_north")),
+        ], Values::Double(centers.iter().map(|c| c.1).collect())),
+        fixed("Mesh2_face_area", vec![face], vec![
+            ("long_name", text("Area of the cell on the unit sphere")),
+            ("units", text("1")),
+            ("mesh", text("Mesh2")),
+            ("location", text("face")),
+        ], Values::Double(network.cells.iter().map(|c| c.polygon.area()).collect())),
+        record("time", vec![time], vec![
+            ("long_name", text("Model time")),
+            ("units", text("1")),
+            ("axis", text("T")),
+        ], NC_DOUBLE, 8),
+        record("step", vec![time], vec![
+            ("long_name", text("Steps taken")),
+        ], NC_INT, 4),
+        record("dt", vec![time], vec![
+            ("long_name", text("Size of the last step")),
+            ("units", text("1")),
+        ], NC_DOUBLE, 8),
+        record("cfl_limiter", vec![time], vec![
+            ("long_name", text("Stability limit the last step was chosen from")),
+            ("flag_values", Attribute::Ints(vec![0, 1, 2, 3, 4])),
+            ("flag_meanings", text("fixed advection diffusion source none")),
+        ], NC_INT, 4),
+        record("cfl_dt", vec![time], vec![
+            ("long_name", text("Value of the stability limit, before max_dt and min_dt")),
+            ("units", text("1")),
+        ], NC_DOUBLE, 8),
+        record("value", vec![time, face], vec![
+            ("long_name", text("Cell value")),
+            ("mesh", text("Mesh2")),
+            ("location", text("face")),
+            ("coordinates", text("Mesh2_face_x Mesh2_face_y")),
+        ], NC_DOUBLE, 8 * n_cells),
+    ];
+    // Other fields follow the cell values in every record, under their own names
+    for field in 1..network.n_fields() {
+        variables.push(record(network.field_name(field), vec![time, face], vec![
+            ("mesh", text("Mesh2")),
+            ("location", text("face")),
+            ("coordinates", text("Mesh2_face_x Mesh2_face_y")),
+        ], NC_DOUBLE, 8 * n_cells));
+    }
+
+    let mut globals = vec![
+        ("Conventions", text("CF-1.8 UGRID-1.0")),
+        ("title", text("isosphere energy-balance model")),
+        ("source", Attribute::Text(format!("isosphere {}", env!("CARGO_PKG_VERSION")))),
+        ("eps1", Attribute::Double(sim.eps1)),
+        ("eps2", Attribute::Double(sim.eps2)),
+        ("scheme", Attribute::Text(format!("{:?}", sim.scheme))),
+        ("courant_number", Attribute::Double(sim.timestep.courant_number)),
+    ];
+    match mesh {
+        Some(mesh_type) => {
+            globals.push(("mesh", Attribute::Text(format!("{:?}", mesh_type))));
+            match mesh_type {
+                meshgen::MeshType::Centroid(n) | meshgen::MeshType::Geodesic(n) | meshgen::MeshType::Goldberg(n) => {
+                    globals.push(("level", Attribute::Int(n as i32)));
+                },
+                meshgen::MeshType::LatLon(n_lat, n_lon) => {
+                    globals.push(("n_lat", Attribute::Int(n_lat as i32)));
+                    globals.push(("n_lon", Attribute::Int(n_lon as i32)));
+                },
+            }
+        },
+        None => globals.push(("mesh", text("external"))),
+    }
+
+    // The header length does not depend on the offsets, so encode it once to measure it
+    let encode_header = |begins: &[u64]| -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(b"CDF\x02");
+        put_u32(&mut out, 0);
+        put_u32(&mut out, NC_DIMENSION);
+        put_u32(&mut out, dimensions.len() as u32);
+        for (name, length) in dimensions.iter() {
+            put_name(&mut out, name);
+            put_u32(&mut out, *length as u32);
+        }
+        put_attributes(&mut out, &globals);
+        put_u32(&mut out, NC_VARIABLE);
+        put_u32(&mut out, variables.len() as u32);
+        for (variable, begin) in variables.iter().zip(begins.iter()) {
+            put_name(&mut out, &variable.name);
+            put_u32(&mut out, variable.dims.len() as u32);
+            for &d in variable.dims.iter() {
+                put_u32(&mut out, d as u32);
+            }
+            put_attributes(&mut out, &variable.attributes);
+            put_u32(&mut out, variable.nc_type);
+            put_u32(&mut out, u32::try_from(variable.size.next_multiple_of(4)).unwrap_or(u32::MAX));
+            out.extend_from_slice(&begin.to_be_bytes());
+        }
+        out
+    };
+    let header_len = encode_header(&vec![0; variables.len()]).len() as u64;
+    let mut begins = Vec::with_capacity(variables.len());
+    let mut offset = header_len;
+    for variable in variables.iter().filter(|v| v.data.is_some()) {
+        begins.push(offset);
+        offset += variable.size.next_multiple_of(4) as u64;
+    }
+    for variable in variables.iter().filter(|v| v.data.is_none()) {
+        begins.push(offset);
+        offset += variable.size.next_multiple_of(4) as u64;
+    }
+
+    let mut data = encode_header(&begins);
+    for variable in variables.iter() {
+        if let Some(values) = &variable.data {
+            values.encode(&mut data);
+            pad(&mut data);
+        }
+    }
+    let record_size = variables.iter().filter(|v| v.data.is_none()).map(|v| v.size.next_multiple_of(4) as u64).sum();
+    (data, record_size)
+}
+
+impl NetcdfWriter {
+    /// Create `path` and write the mesh of `sim` and the run parameters to it.
+    ///
+    /// `mesh` describes how the mesh was generated, if it was; its
+    /// subdivision level is recorded as the `level` attribute.
+    pub fn create(path: &Path, sim: &driver::Simulation, mesh: Option<meshgen::MeshType>) -> std::io::Result<NetcdfWriter> {
+        let (data, _) = encode(sim, mesh);
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(&data)?;
+        out.flush()?;
+        Ok(NetcdfWriter { out, n_cells: sim.network.cells.len(), n_fields: sim.network.n_fields(), records: 0 })
+    }
+    /// Continue the file at `path` for a run restarted as `sim`, or create it if there is none.
+    ///
+    /// Records from before the step `sim` restarts at are kept; later ones were
+    /// written after its checkpoint and are removed. A file written for a
+    /// different mesh or different run parameters is refused rather than overwritten.
+    pub fn resume(path: &Path, sim: &driver::Simulation, mesh: Option<meshgen::MeshType>) -> std::io::Result<NetcdfWriter> {
+        if !path.exists() {
+            return NetcdfWriter::create(path, sim, mesh);
+        }
+        let (data, record_size) = encode(sim, mesh);
+        let mut file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+        let mut header = vec![0; data.len()];
+        let len = file.metadata()?.len();
+        let refuse = || std::io::Error::other(format!("{} was not written by this run; refusing to overwrite it", path.display()));
+        if len < data.len() as u64 {
+            return Err(refuse());
+        }
+        file.read_exact(&mut header)?;
+        let records = u32::from_be_bytes(header[4..8].try_into().expect("four bytes"));
+        if header[..4] != data[..4] || header[8..] != data[8..] || len != data.len() as u64 + records as u64 * record_size {
+            return Err(refuse());
+        }
+        // The step count is the second variable of every record, after the time
+        let mut kept = 0;
+        while kept < records {
+            let mut step = [0; 4];
+            file.seek(SeekFrom::Start(data.len() as u64 + kept as u64 * record_size + 8))?;
+            file.read_exact(&mut step)?;
+            if i32::from_be_bytes(step) as i64 >= sim.steps as i64 { break; }
+            kept += 1;
+        }
+        file.set_len(data.len() as u64 + kept as u64 * record_size)?;
+        file.seek(SeekFrom::Start(4))?;
+        file.write_all(&kept.to_be_bytes())?;
+        file.flush()?;
+        Ok(NetcdfWriter { out: BufWriter::new(file), n_cells: sim.network.cells.len(), n_fields: sim.network.n_fields(), records: kept })
+    }
+    /// Number of snapshots written so far
+    pub fn len(self: &NetcdfWriter) -> usize {
+        self.records as usize
+    }
+    pub fn is_empty(self: &NetcdfWriter) -> bool {
+        self.records == 0
+    }
+    /// Append the current state of `sim` as the next record
+    pub fn write_step(self: &mut NetcdfWriter, sim: &driver::Simulation) -> std::io::Result<()> {
+        if sim.network.cells.len() != self.n_cells {
+            return Err(std::io::Error::other("the mesh has changed since the file was created"));
+        }
+        if sim.network.n_fields() != self.n_fields {
+            return Err(std::io::Error::other("fields have been added since the file was created"));
+        }
+        let mut data = Vec::with_capacity(32 + 8 * self.n_cells * self.n_fields);
+        data.extend_from_slice(&sim.time.to_be_bytes());
+        data.extend_from_slice(&(sim.steps as i32).to_be_bytes());
+        data.extend_from_slice(&sim.last_dt.unwrap_or(0.0).to_be_bytes());
+        data.extend_from_slice(&limiter_code(&sim.limiter).to_be_bytes());
+        data.extend_from_slice(&sim.limiter.map_or(0.0, |l| l.dt()).to_be_bytes());
+        for field in 0..self.n_fields {
+            Values::Double(sim.network.values(field)).encode(&mut data);
+        }
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.write_all(&data)?;
+        // Keep the record count in the header current so a partial run can still be read
+        self.records += 1;
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&self.records.to_be_bytes())?;
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrate::TimeScheme;
+    use crate::meshgen::MeshType;
+
+    #[test]
+    fn test_write_records() {
+        let path = std::env::temp_dir().join(format!("isosphere_netcdf_{}.nc", std::process::id()));
+        let mesh = MeshType::Geodesic(1);
+        let mut network = pgen::init_mesh(mesh, pgen::InitialCondition::Radiative).unwrap();
+        let tracer = network.add_field("tracer", network.cells.iter().map(|c| c.center().phi.cos() + 1.0).collect()).unwrap();
+        let mut sim = driver::Simulation::new(network, 1.0, 0.5, TimeScheme::ForwardEuler);
+        let mut writer = NetcdfWriter::create(&path, &sim, Some(mesh)).unwrap();
+        let header_len = std::fs::metadata(&path).unwrap().len();
+        writer.write_step(&sim).unwrap();
+        sim.step(None).unwrap();
+        writer.write_step(&sim).unwrap();
+        drop(writer);
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(&bytes[..4], b"CDF\x02");
+        assert_eq!(u32::from_be_bytes(bytes[4..8].try_into().unwrap()), 2);
+        let n = sim.network.cells.len();
+        let record_len = 8 + 4 + 8 + 4 + 8 + 2 * 8 * n;
+        assert_eq!(bytes.len() as u64, header_len + 2 * record_len as u64);
+        assert!(bytes[..header_len as usize].windows(6).any(|w| w == b"tracer"));
+
+        // The second record ends with the values and the tracer after one step
+        let last = &bytes[bytes.len() - 16 * n..];
+        let (values, tracers) = last.split_at(8 * n);
+        for (i, (value, tracer_value)) in values.chunks(8).zip(tracers.chunks(8)).enumerate() {
+            assert_eq!(f64::from_be_bytes(value.try_into().unwrap()), sim.network.cells[i].value);
+            assert_eq!(f64::from_be_bytes(tracer_value.try_into().unwrap()), sim.network.value(tracer, i));
+        }
+        let code = &bytes[bytes.len() - record_len + 20..bytes.len() - record_len + 24];
+        assert_eq!(i32::from_be_bytes(code.try_into().unwrap()), limiter_code(&sim.limiter));
+
+        // Resuming at step 1 keeps the record of step 0 and replaces the other
+        let mut writer = NetcdfWriter::resume(&path, &sim, Some(mesh)).unwrap();
+        assert_eq!(writer.len(), 1);
+        writer.write_step(&sim).unwrap();
+        drop(writer);
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+        // A run without the tracer is refused and the file left alone
+        let other = driver::Simulation::new(pgen::init_mesh(mesh, pgen::InitialCondition::Radiative).unwrap(), 1.0, 0.5, TimeScheme::ForwardEuler);
+        assert!(NetcdfWriter::resume(&path, &other, Some(mesh)).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
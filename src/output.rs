@@ -0,0 +1,32 @@
+//! Write meshes and cell values to disk
+//!
+//!
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use super::grid;
+
+/// Write one row per cell: index, centroid longitude and latitude in degrees, area,
+/// value and then the other fields of the network
+pub fn write_csv(network: &grid::GridNetwork, path: &Path) -> std::io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let fields: Vec<usize> = (1..network.n_fields()).collect();
+    write!(out, "cell,lon,lat,area,value")?;
+    for &field in fields.iter() {
+        write!(out, ",{}", network.field_name(field))?;
+    }
+    writeln!(out)?;
+    for (i, cell) in network.cells.iter().enumerate() {
+        let center = cell.center();
+        let lon = center.phi.to_degrees();
+        let lat = 90.0 - center.theta.to_degrees();
+        write!(out, "{},{},{},{},{}", i, lon, lat, cell.polygon.area(), cell.value)?;
+        for &field in fields.iter() {
+            write!(out, ",{}", network.value(field, i))?;
+        }
+        writeln!(out)?;
+    }
+    out.flush()
+}
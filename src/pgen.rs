@@ -1,17 +1,24 @@
+//! Problem Generator
+//! 
+//! 
+
 use core::f64;
-use std::{error, f64::consts::PI};
 
 use log::{info,error};
-use std::time::{Instant,Duration};
-
-/// Problem Generator
-/// 
-/// 
+use std::f64::consts::PI;
+use std::time::Instant;
 
-use super::{grid, coords,meshgen::icoshedron};
+use super::{grid, coords, forcing, integrate, meshgen::MeshType};
+use super::error::Error;
 
-static COURANT_NUMBER: f64 = 0.4;
+/// Default safety factor applied to the explicit stability limits
+pub static COURANT_NUMBER: f64 = 0.4;
+/// Timestep used when nothing limits it
+pub static DEFAULT_TIMESTEP: f64 = 1.0;
 
+#[allow(non_camel_case_types)]
+#[derive(Clone,Copy,Debug,PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum CFL_Limiter {
     AdvectionLimited(f64),
     DiffusionLimited(f64),
@@ -19,161 +26,200 @@ pub enum CFL_Limiter {
     NoLimit(f64)
 }
 
-pub fn get_timestep(eps1:f64,eps2:f64,max_temp:f64,dx:f64)->CFL_Limiter {
+impl CFL_Limiter {
+    pub fn dt(self:&CFL_Limiter)->f64 {
+        match *self {
+            CFL_Limiter::AdvectionLimited(dt) => dt,
+            CFL_Limiter::DiffusionLimited(dt) => dt,
+            CFL_Limiter::SourceLimited(dt) => dt,
+            CFL_Limiter::NoLimit(dt) => dt
+        }
+    }
+}
+
+pub fn get_timestep(courant_number:f64,eps1:f64,eps2:f64,max_temp:f64,dx:f64)->CFL_Limiter {
     let adv = {
         if eps1 == 0.0 { f64::INFINITY }
-        else {COURANT_NUMBER * dx / eps1}
+        else {courant_number * dx / eps1}
     };
     let diff = {
         if eps2 == 0.0 { f64::INFINITY }
-        else {COURANT_NUMBER * dx.powi(2) / eps2 / 2.0}
+        else {courant_number * dx.powi(2) / eps2 / 2.0}
     };
     let source = {
         if max_temp == 0.0 { f64::INFINITY }
-        else { COURANT_NUMBER / 4.0 / max_temp.powi(3)}
+        else { courant_number / 4.0 / max_temp.powi(3)}
     };
 
-    if adv < diff && adv < source { CFL_Limiter::AdvectionLimited(adv) }
-    else if diff < adv && diff < source { CFL_Limiter::DiffusionLimited(diff) }
-    else if source < adv && source < diff { CFL_Limiter::SourceLimited(source) }
-    else { CFL_Limiter::NoLimit(1.0) }
-}
-
-/// max(cos(x),0)
-fn pcos(x:f64)->f64{
-    let cos = x.cos();
-    if cos > 0.0 { cos }
-    else {0.0}
+    // Ties go to the first limiter in this order
+    let dt = adv.min(diff).min(source);
+    if dt == f64::INFINITY { CFL_Limiter::NoLimit(DEFAULT_TIMESTEP) }
+    else if adv == dt { CFL_Limiter::AdvectionLimited(adv) }
+    else if diff == dt { CFL_Limiter::DiffusionLimited(diff) }
+    else { CFL_Limiter::SourceLimited(source) }
 }
 
-pub fn incident_flux(p: &grid::GridCell) -> f64 {
-    let area = p.polygon.area();
-    let centroid = p.polygon.center();
-    centroid.theta.sin() * area * pcos(centroid.phi)
+/// Starlight absorbed by cell `p`, integrated over it by `light.rule`.
+///
+/// The albedo is taken at the current value of the cell, so the ice-albedo
+/// feedback acts through every explicit evaluation of this flux.
+pub fn incident_flux(p: &grid::GridCell, light: &forcing::Illumination) -> f64 {
+    (1.0 - p.surface.albedo_at(p.value)) * light.flux_over(&p.polygon)
 }
 
 pub fn thermal_flux(p: &grid::GridCell) -> f64 {
     let area = p.polygon.area();
     let temperature = p.value;
-    area * temperature.powi(4)
+    p.surface.emissivity * area * temperature.powi(4)
 }
 
-fn adv_flux_across_edge(edge: &coords::Edge,a: &grid::GridCell,b: &grid::GridCell) -> Result<f64,&'static str> {
-    if !a.polygon.to_edges().contains(&edge) {
-        Err("Edge not in polygon a")
+/// Coefficients `(c_a, c_b)` of the upwind advective flux from cell `i` to cell `j`
+/// across `edge`, so that the flux is `c_a * a.value + c_b * b.value`
+fn adv_coefficients_across_edge(edge: &coords::Edge,i: usize,j: usize,network: &grid::GridNetwork) -> Result<(f64,f64),Error> {
+    let a = &network.cells[i];
+    let b = &network.cells[j];
+    if !a.polygon.to_edges().contains(edge) {
+        Err(Error::EdgeNotInPolygon{cell: i})
     }
-    else if !b.polygon.to_edges().contains(&edge) {
-        Err("Edge not in polygon b")
+    else if !b.polygon.to_edges().contains(edge) {
+        Err(Error::EdgeNotInPolygon{cell: j})
     }
     else {
-        let nhat = edge.get_great_circle().nhat();
+        let nhat = edge.get_great_circle()?.nhat()?;
         let nhat = {
-            let (ax,ay,az) = a.polygon.center().cart().unwrap();
-            let (bx,by,bz) = b.polygon.center().cart().unwrap();
+            let (ax,ay,az) = a.center().cart()?;
+            let (bx,by,bz) = b.center().cart()?;
             let dx = bx - ax;
             let dy = by - ay;
             let dz = bz - az;
-            let (nx,ny,nz) = nhat.cart().unwrap();
+            let (nx,ny,nz) = nhat.cart()?;
             let _dot = dx*nx + dy*ny + dz*nz;
             if _dot < 0.0 {
-                coords::Coordinate::from_cart(-nx, -ny, -nz).unwrap()
+                coords::Coordinate::from_cart(-nx, -ny, -nz)?
             }
             else { nhat }
         };
-        let upwind_i = {
-            if coords::phihat_dot_nhat(&edge.a,&nhat) > 0.0 { 
-                a
-             } else { 
-                b
-              }
-        };
-        let upwind_m = {
-            if coords::phihat_dot_nhat(&edge.midpoint().unwrap(),&nhat) > 0.0 { a } else { b }
-        };
-        let upwind_f = {
-            if coords::phihat_dot_nhat(&edge.b,&nhat) > 0.0 { a } else { b }
-        };
+        let mid = edge.midpoint()?;
+        let mut c_a = 0.0;
+        let mut c_b = 0.0;
+        // Simpson's rule over the edge, taking each sample from the upwind cell
+        for (node, weight) in [(&edge.a, 1.0), (&mid, 4.0), (&edge.b, 1.0)] {
+            let f = weight * node.theta.sin() * coords::phihat_dot_nhat(node, &nhat);
+            if coords::phihat_dot_nhat(node, &nhat) > 0.0 { c_a += f } else { c_b += f }
+        }
+        Ok((edge.len() / 6.0 * c_a, edge.len() / 6.0 * c_b))
+    }
+}
+
+fn adv_flux_across_edge(edge: &coords::Edge,i: usize,j: usize,network: &grid::GridNetwork,field: grid::FieldId) -> Result<f64,Error> {
+    let (c_a, c_b) = adv_coefficients_across_edge(edge,i,j,network)?;
+    Ok(c_a * network.value(field,i) + c_b * network.value(field,j))
+}
 
-        let f_i = upwind_i.value * edge.a.theta.sin() * coords::phihat_dot_nhat(&edge.a, &nhat);
-        let f_m = upwind_m.value * edge.midpoint().unwrap().theta.sin() * coords::phihat_dot_nhat(&edge.midpoint().unwrap(), &nhat);
-        let f_f = upwind_f.value * edge.b.theta.sin() * coords::phihat_dot_nhat(&edge.b, &nhat);
-        
-        Ok(edge.len() / 6.0 * (f_i + 4.0*f_m + f_f))  
+/// Find the cell on the other side of edge `side` of cell `i`
+fn neighbor_across(i: usize, side: usize, network: &grid::GridNetwork) -> Result<usize,Error> {
+    let topology = network.topology();
+    let e = topology.cell_edges[i][side];
+    let neighbor_candidates = &topology.edges[e].cells;
+    if neighbor_candidates.len() > 2 {
+        let edge = &topology.edges[e];
+        let mut s = String::from("This edge separates more than two cells!");
+        s += &format!("\nEdge from {:}",topology.vertices[edge.a]);
+        s += &format!("\n to       {:}",topology.vertices[edge.b]);
+        s += "\nNeighbors:";
+        for &can in neighbor_candidates.iter() {
+            s += &format!("\n{:}",network.cells[can].polygon);
+        }
+        error!("{}",s);
+        return Err(Error::NonManifoldEdge{cell: i, cells: neighbor_candidates.clone()});
     }
+    topology.across(i,e).next().ok_or(Error::BoundaryEdge{cell: i})
 }
 
-pub fn advective_flux(p: &grid::GridCell, network: &grid::GridNetwork) -> Result<f64,&'static str> {
+/// Net advective flux of `field` out of cell `i`
+pub fn advective_flux(i: usize, network: &grid::GridNetwork, field: grid::FieldId) -> Result<f64,Error> {
+    let p = &network.cells[i];
     let mut flux = 0.0;
-    let sides = p.polygon.to_edges();
-    for side in sides.iter() {
-        let neighbor_candidates = network.query_edge(side);
-        if neighbor_candidates.len() > 2 { 
-            let mut s = String::from("This edge separates more than two cells!");
-            s += &format!("\nEdge from {:}",side.a);
-            s += &format!("\n to       {:}",side.b);
-            s += &format!("\nNeighbors:");
-            for can in neighbor_candidates.iter() {
-                s += &format!("\n{:}",can.polygon);
-            }
-            error!("{}",s);
-            return Err("This edge separates more than two cells!");
-        }
-        let neighbor = {
-            if neighbor_candidates[0] == p { neighbor_candidates[1] }
-            else { neighbor_candidates[0] }
-        };
-        flux += adv_flux_across_edge(side,p,neighbor).unwrap();
+    for (side, edge) in p.polygon.to_edges().iter().enumerate() {
+        let neighbor = neighbor_across(i,side,network)?;
+        flux += adv_flux_across_edge(edge,i,neighbor,network,field)?;
     }
     Ok(flux)
 }
 
-fn diff_flux_across_edge(edge: &coords::Edge,a: &grid::GridCell,b: &grid::GridCell) -> Result<f64,&'static str> {
-    let dist = a.polygon.center().angle_between(&b.polygon.center());
+/// Conductance of an edge: the diffusive flux from `b` into `a` is this times `b.value - a.value`
+fn diff_coefficient_across_edge(edge: &coords::Edge,a: &grid::GridCell,b: &grid::GridCell) -> f64 {
+    let dist = a.center().angle_between(&b.center());
     let len_boundary = edge.len();
-    Ok((b.value - a.value) / dist * len_boundary)
+    len_boundary / dist
 }
 
-pub fn diffusive_flux(p: &grid::GridCell, network: &grid::GridNetwork) -> Result<f64,&'static str> {
+
+/// Linear coefficients of the transport fluxes across one side of a cell
+pub struct SideCoefficients {
+    pub neighbor: usize,
+    /// Outgoing advective flux is `adv_self * T_self + adv_neighbor * T_neighbor`
+    pub adv_self: f64,
+    pub adv_neighbor: f64,
+    /// Incoming diffusive flux is `diff * (T_neighbor - T_self)`
+    pub diff: f64,
+}
+
+/// Coefficients of `advective_flux` and `diffusive_flux` for each side of cell `i`.
+///
+/// Both fluxes are linear in the cell values, so these are all that is needed
+/// to assemble the transport operator for an implicit step.
+pub fn side_coefficients(i: usize, network: &grid::GridNetwork) -> Result<Vec<SideCoefficients>,Error> {
+    let p = &network.cells[i];
+    let mut sides = Vec::new();
+    for (side, edge) in p.polygon.to_edges().iter().enumerate() {
+        let neighbor = neighbor_across(i,side,network)?;
+        let n = &network.cells[neighbor];
+        let (adv_self, adv_neighbor) = adv_coefficients_across_edge(edge,i,neighbor,network)?;
+        let diff = diff_coefficient_across_edge(edge,p,n);
+        sides.push(SideCoefficients{neighbor, adv_self, adv_neighbor, diff});
+    }
+    Ok(sides)
+}
+
+/// Net diffusive flux of `field` into cell `i`
+pub fn diffusive_flux(i: usize, network: &grid::GridNetwork, field: grid::FieldId) -> Result<f64,Error> {
+    let p = &network.cells[i];
     let mut flux = 0.0;
-    let sides = p.polygon.to_edges();
-    for side in sides.iter() {
-        let neighbor_candidates = network.query_edge(side);
-        if neighbor_candidates.len() > 2 { 
-            let mut s = String::from("This edge separates more than two cells!");
-            s += &format!("\nEdge from {:}",side.a);
-            s += &format!("\n to       {:}",side.b);
-            s += &format!("\nNeighbors:");
-            for can in neighbor_candidates.iter() {
-                s += &format!("\n{:}",can.polygon);
-            }
-            error!("{}",s);
-            return Err("This edge separates more than two cells!");
-        }
-        let neighbor = {
-            if neighbor_candidates[0] == p { neighbor_candidates[1] }
-            else { neighbor_candidates[0] }
-        };
-        flux += diff_flux_across_edge(side,p,neighbor).unwrap();
+    for (side, edge) in p.polygon.to_edges().iter().enumerate() {
+        let j = neighbor_across(i,side,network)?;
+        let gradient = network.value(field,j) - network.value(field,i);
+        flux += gradient * diff_coefficient_across_edge(edge,p,&network.cells[j]);
     }
     Ok(flux)
 }
 
-fn format_debug_output(p: &grid::GridCell, network: &grid::GridNetwork) -> String {
+/// Rate of change of `field` in cell `i` from advection and diffusion alone,
+/// for fields such as passive tracers that are carried by the same flow as the heat
+pub fn transport_tendency(i: usize, network: &grid::GridNetwork, field: grid::FieldId, eps1: f64, eps2: f64) -> Result<f64,Error> {
+    let area = network.cells[i].polygon.area();
+    Ok((diffusive_flux(i,network,field)? * eps2 - advective_flux(i,network,field)? * eps1) / area)
+}
+
+fn format_debug_output(i: usize, network: &grid::GridNetwork) -> String {
+    let p = &network.cells[i];
     let mut s = String::from("Cell\n");
     s += "====\n";
+    s += &format!("Index: {}\n",i);
     s += &format!("Value: {}\n",p.value);
     s += &format!("Area:  {}\n",p.polygon.area());
-    s += &format!("Vertices:\n");
+    s += &format!("Surface: {:?}\n",p.surface);
+    s += "Vertices:\n";
     for v in p.polygon.nodes.iter() {
         s += &format!("\t{:2}\n",v);
     }
-    let neighbors = network.query_neighbors(&p);
-    s += &format!("Neighbors:\n");
-    for (i, n) in neighbors.iter().enumerate() {
-        s += &format!("{})\n",i);
+    s += "Neighbors:\n";
+    for &j in network.neighbors(i).iter() {
+        let n = &network.cells[j];
+        s += &format!("{})\n",j);
         s += &format!("Value: {}\n",n.value);
         s += &format!("Area:  {}\n",n.polygon.area());
-        s += &format!("Vertices:\n");
+        s += "Vertices:\n";
         for v in n.polygon.nodes.iter() {
             s += &format!("\t{:2}\n",v);
         }
@@ -183,16 +229,26 @@ fn format_debug_output(p: &grid::GridCell, network: &grid::GridNetwork) -> Strin
 
 
 
-pub fn get_next_value(p: &grid::GridCell, network: &grid::GridNetwork,eps1: f64, eps2: f64, dt: f64) -> Result<f64,&'static str> {
-    let area = p.polygon.area();
-    let _incident_flux = incident_flux(p) * dt / area;
+/// Rate of change of the value of cell `i`: the same terms as `get_next_value`, per unit time
+pub fn get_tendency(i: usize, network: &grid::GridNetwork,eps1: f64, eps2: f64, light: &forcing::Illumination) -> Result<f64,Error> {
+    let p = &network.cells[i];
+    let area = p.polygon.area() * p.surface.heat_capacity;
+    let net_flux = incident_flux(p,light) - thermal_flux(p) - advective_flux(i,network,grid::VALUE)? * eps1 + diffusive_flux(i,network,grid::VALUE)? * eps2;
+    Ok(net_flux / area)
+}
+
+/// Forward Euler update of cell `i`. The net flux is spread over the area times the heat capacity.
+pub fn get_next_value(i: usize, network: &grid::GridNetwork,eps1: f64, eps2: f64, light: &forcing::Illumination, dt: f64) -> Result<f64,Error> {
+    let p = &network.cells[i];
+    let area = p.polygon.area() * p.surface.heat_capacity;
+    let _incident_flux = incident_flux(p,light) * dt / area;
     let _thermal_flux = -thermal_flux(p) * dt / area;
-    let _advective_flux = -advective_flux(p,network)? * dt / area * eps1;
-    let _diffusive_flux = diffusive_flux(p,network)? * dt / area * eps2;
+    let _advective_flux = -advective_flux(i,network,grid::VALUE)? * dt / area * eps1;
+    let _diffusive_flux = diffusive_flux(i,network,grid::VALUE)? * dt / area * eps2;
     let next_value = p.value + _incident_flux + _thermal_flux + _advective_flux + _diffusive_flux;
     if next_value < 0.0 {
         let mut s = String::from("Negative temperature in cell update");
-        s += &format!("\n{:}\n\n",format_debug_output(p,network));
+        s += &format!("\n{:}\n\n",format_debug_output(i,network));
         s += &format!("\nIncident flux: {}",_incident_flux);
         s += &format!("\nThermal flux: {}",_thermal_flux);
         s += &format!("\nAdvective flux: {}",_advective_flux);
@@ -200,33 +256,73 @@ pub fn get_next_value(p: &grid::GridCell, network: &grid::GridNetwork,eps1: f64,
         s += &format!("\nNext value: {}",next_value);
         error!("{}",s);
 
-        return Err("Negative temperature");
+        return Err(Error::NegativeTemperature{cell: Some(i), value: next_value});
     }
     Ok(next_value)
 }
 
+#[derive(Clone,Copy,Debug,PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum InitialCondition {
     Constant(f64),
     Radiative
 }
 
-pub fn init_mesh(subdivisions: u32, initial_condition: InitialCondition) -> grid::GridNetwork {
-    let polygons = icoshedron(subdivisions);
+/// Set up `initial_condition` on a generated mesh, lit by the default `forcing::Illumination`
+pub fn init_mesh(mesh_type: MeshType, initial_condition: InitialCondition) -> Result<grid::GridNetwork,Error> {
+    init_network(mesh_type.generate()?, initial_condition, &forcing::Illumination::default())
+}
+
+/// Set up `initial_condition` on an arbitrary list of cells, such as a mesh read from a file
+pub fn init_network(polygons: Vec<coords::Polygon>, initial_condition: InitialCondition, light: &forcing::Illumination) -> Result<grid::GridNetwork,Error> {
     let mut cells: Vec<grid::GridCell> = Vec::new();
     for p in polygons.iter() {
         let value = match initial_condition {
             InitialCondition::Constant(c) => c,
-            InitialCondition::Radiative => pcos(p.center().phi) * p.center().theta.sin()
+            InitialCondition::Radiative => light.flux_at(&p.center()?)
         };
-        cells.push(grid::GridCell::new(p.clone(),value));
+        cells.push(grid::GridCell::new(p.clone(),value)?);
     }
-    grid::GridNetwork::new(cells)
+    Ok(grid::GridNetwork::new(cells))
 }
 
-pub fn get_next_mesh(network: grid::GridNetwork, eps1: f64, eps2: f64) -> grid::GridNetwork {
-    let max_temp = network.max_value();
-    let dx = (4.0 * PI / network.cells.len() as f64).sqrt();
-    let dt_result = get_timestep(eps1, eps2, max_temp, dx);
+/// Cell size the explicit stability limits are measured against
+#[derive(Clone,Copy,Debug,Default,PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LengthScale {
+    /// `sqrt(4 pi / N)`, the side of a cell of the mean area
+    #[default]
+    MeanCellSize,
+    /// Smallest distance between the centers of neighboring cells, for meshes
+    /// such as the lat-lon grid whose cells narrow far below the mean size
+    MinCellSpacing
+}
+
+impl LengthScale {
+    pub fn length(self:&LengthScale, network: &grid::GridNetwork) -> f64 {
+        match self {
+            LengthScale::MeanCellSize => (4.0 * PI / network.cells.len() as f64).sqrt(),
+            LengthScale::MinCellSpacing => network.min_cell_spacing()
+        }
+    }
+}
+
+/// Explicit stability limit on the timestep for the current state of `network`.
+///
+/// A low heat capacity speeds every term up, so the transport limits use the
+/// strengths divided by the smallest heat capacity, and the source limit the
+/// temperature of a black-body cell of unit heat capacity that cools as fast
+/// as the fastest cell.
+pub fn cfl_timestep(network: &grid::GridNetwork, courant_number: f64, length_scale: LengthScale, eps1: f64, eps2: f64) -> CFL_Limiter {
+    let min_capacity = network.cells.iter().map(|c| c.surface.heat_capacity).fold(f64::INFINITY, f64::min);
+    let max_temp = network.cells.iter().map(|c| c.value * (c.surface.emissivity / c.surface.heat_capacity).cbrt()).fold(0.0, f64::max);
+    let dx = length_scale.length(network);
+    get_timestep(courant_number, eps1 / min_capacity, eps2 / min_capacity, max_temp, dx)
+}
+
+/// One forward Euler step at the stability limit scaled by `courant_number`
+pub fn get_next_mesh(network: grid::GridNetwork, courant_number: f64, eps1: f64, eps2: f64, light: &forcing::Illumination) -> Result<grid::GridNetwork,Error> {
+    let dt_result = cfl_timestep(&network, courant_number, LengthScale::MeanCellSize, eps1, eps2);
     let dt =match dt_result {
         CFL_Limiter::AdvectionLimited(adv) => {info!("Advection limited timestep: {}",adv); adv},
         CFL_Limiter::DiffusionLimited(diff) => {info!("Diffusion limited timestep: {}",diff); diff},
@@ -235,29 +331,50 @@ pub fn get_next_mesh(network: grid::GridNetwork, eps1: f64, eps2: f64) -> grid::
     };
     let mut new_cells: Vec<grid::GridCell> = Vec::new();
     let start_time = Instant::now();
-    for cell in network.cells.iter() {
-        let value = get_next_value(cell,&network,eps1,eps2,dt).unwrap();
-        new_cells.push(grid::GridCell::new(cell.polygon.clone(),value));
+    for (i, cell) in network.cells.iter().enumerate() {
+        let value = get_next_value(i,&network,eps1,eps2,light,dt)?;
+        new_cells.push(cell.with_value(value)?);
     }
+    let mut next = network.with_cells(new_cells)?;
+    integrate::step_fields(&network,&mut next,eps1,eps2,dt,integrate::TimeScheme::ForwardEuler)?;
     let end_time = Instant::now();
     info!("Mesh update time: {:?}",end_time - start_time);
-    grid::GridNetwork::new(new_cells)
+    Ok(next)
 
 }
 
+#[derive(Clone,Copy,Debug,PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum EnergyBalance {
     Balanced(f64),
     ExcessIncident(f64),
     ExcessThermal(f64),
 }
 
-pub fn check_energy_balance(network: &grid::GridNetwork) -> EnergyBalance {
+impl EnergyBalance {
+    /// Excess of incident over thermal energy, as a percentage of the incident energy
+    pub fn percent(self:&EnergyBalance) -> f64 {
+        match *self {
+            EnergyBalance::Balanced(pct) => pct,
+            EnergyBalance::ExcessIncident(pct) => pct,
+            EnergyBalance::ExcessThermal(pct) => pct
+        }
+    }
+}
+
+/// Total incident and thermal energy flux over the whole sphere
+pub fn energy_totals(network: &grid::GridNetwork, light: &forcing::Illumination) -> (f64, f64) {
     let mut energy_in = 0.0;
     let mut energy_out = 0.0;
     for cell in network.cells.iter() {
-        energy_in += incident_flux(cell);
+        energy_in += incident_flux(cell,light);
         energy_out += thermal_flux(cell);
     }
+    (energy_in, energy_out)
+}
+
+pub fn check_energy_balance(network: &grid::GridNetwork, light: &forcing::Illumination) -> EnergyBalance {
+    let (energy_in, energy_out) = energy_totals(network,light);
     let excess = energy_in - energy_out;
     let excess_as_pct = excess / energy_in * 100.0;
     if excess_as_pct.abs() < 1.0 {
@@ -272,4 +389,79 @@ pub fn check_energy_balance(network: &grid::GridNetwork) -> EnergyBalance {
         log::warn!("Excess thermal energy: {}%",excess_as_pct);
         EnergyBalance::ExcessThermal(excess_as_pct)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_timestep() {
+        assert!(matches!(get_timestep(0.4, 1.0, 0.0, 0.0, 0.1), CFL_Limiter::AdvectionLimited(dt) if (dt - 0.04).abs() < 1e-15));
+        assert!(matches!(get_timestep(0.4, 0.0, 1.0, 0.0, 0.1), CFL_Limiter::DiffusionLimited(dt) if (dt - 0.002).abs() < 1e-15));
+        assert!(matches!(get_timestep(0.4, 0.0, 0.0, 1.0, 0.1), CFL_Limiter::SourceLimited(dt) if (dt - 0.1).abs() < 1e-15));
+        // Equal limits still limit the step
+        assert!(matches!(get_timestep(0.4, 0.0, 2.0, 1.0, 1.0), CFL_Limiter::DiffusionLimited(dt) if dt == 0.1));
+        assert!(matches!(get_timestep(0.4, 4.0, 0.0, 1.0, 1.0), CFL_Limiter::AdvectionLimited(dt) if dt == 0.1));
+        assert!(matches!(get_timestep(0.4, 0.0, 0.0, 0.0, 0.1), CFL_Limiter::NoLimit(dt) if dt == DEFAULT_TIMESTEP));
+    }
+
+    #[test]
+    fn test_cfl_length_scale() {
+        let network = init_mesh(MeshType::Geodesic(2), InitialCondition::Constant(1.0)).unwrap();
+        let dx = (4.0 * PI / network.cells.len() as f64).sqrt();
+        assert_eq!(cfl_timestep(&network, 0.4, LengthScale::MeanCellSize, 0.0, 1.0), get_timestep(0.4, 0.0, 1.0, 1.0, dx));
+        // The pole cells of a lat-lon mesh are far narrower than the mean
+        let network = init_mesh(MeshType::LatLon(18, 36), InitialCondition::Constant(1.0)).unwrap();
+        let mean = cfl_timestep(&network, 0.4, LengthScale::MeanCellSize, 0.0, 1.0).dt();
+        let min = cfl_timestep(&network, 0.4, LengthScale::MinCellSpacing, 0.0, 1.0).dt();
+        assert!(min < 0.1 * mean);
+    }
+
+    #[test]
+    fn test_field_fluxes() {
+        let mut network = init_mesh(MeshType::Goldberg(1), InitialCondition::Radiative).unwrap();
+        let copy = network.add_field("copy", network.values(grid::VALUE)).unwrap();
+        let tracer = network.add_field("tracer", network.cells.iter().map(|c| c.center().theta.cos() + 2.0).collect()).unwrap();
+        assert_eq!(network.field_id("tracer"), Some(tracer));
+        assert_eq!(network.field_name(grid::VALUE), grid::VALUE_NAME);
+        assert!(network.add_field("copy", vec![0.0; network.cells.len()]).is_err());
+        assert!(network.add_field("a,b", vec![0.0; network.cells.len()]).is_err());
+        for name in ["-x", "1x", "value", "cell", "lon", "lat", "area", "time", "Mesh2_face_x"] {
+            assert!(network.add_field(name, vec![0.0; network.cells.len()]).is_err());
+        }
+        let (mut adv, mut diff) = (0.0, 0.0);
+        for i in 0..network.cells.len() {
+            assert_eq!(advective_flux(i, &network, copy).unwrap(), advective_flux(i, &network, grid::VALUE).unwrap());
+            assert_eq!(diffusive_flux(i, &network, copy).unwrap(), diffusive_flux(i, &network, grid::VALUE).unwrap());
+            adv += advective_flux(i, &network, tracer).unwrap();
+            diff += diffusive_flux(i, &network, tracer).unwrap();
+        }
+        // Transport only moves a field between cells
+        assert!(adv.abs() < 1e-12 && diff.abs() < 1e-12);
+
+        // A uniform field does not change, and a tracer keeps its total
+        let uniform = network.add_field("uniform", vec![2.0; network.cells.len()]).unwrap();
+        let (eps1, eps2) = (0.5, 2.0);
+        let mut total = 0.0;
+        for i in 0..network.cells.len() {
+            let area = network.cells[i].polygon.area();
+            let expected = (eps2 * diffusive_flux(i, &network, tracer).unwrap() - eps1 * advective_flux(i, &network, tracer).unwrap()) / area;
+            let tendency = transport_tendency(i, &network, tracer, eps1, eps2).unwrap();
+            assert!((tendency - expected).abs() < 1e-12);
+            assert!(transport_tendency(i, &network, uniform, eps1, eps2).unwrap().abs() < 1e-12);
+            total += area * tendency;
+        }
+        assert!(total.abs() < 1e-12);
+
+        // A step of the energy balance carries the other fields by the same flow
+        let dt = cfl_timestep(&network, 0.2, LengthScale::MeanCellSize, eps1, eps2).dt();
+        let tendencies: Vec<f64> = (0..network.cells.len()).map(|i| transport_tendency(i, &network, tracer, eps1, eps2).unwrap()).collect();
+        let before = network.values(tracer);
+        let next = get_next_mesh(network, 0.2, eps1, eps2, &forcing::Illumination::default()).unwrap();
+        assert_eq!(next.n_fields(), 4);
+        for i in 0..next.cells.len() {
+            assert!((next.value(tracer, i) - before[i] - dt * tendencies[i]).abs() < 1e-12);
+        }
+    }
+}
@@ -0,0 +1,526 @@
+//! Maps of cell values
+//!
+//! A `GridNetwork` is drawn on a map in one of several `Projection`s,
+//! coloured with a `Colormap` and written as a PPM or PNG file, or printed
+//! as an ASCII heatmap. Equirectangular maps are resampled by finding the
+//! cell that contains each pixel; the other projections fill the projected
+//! outline of every cell.
+
+use std::f64::consts::{FRAC_PI_2, PI, SQRT_2};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use super::coords;
+use super::error::Error;
+use super::grid;
+
+/// Colour of pixels that are not inside any cell
+static BACKGROUND: [u8; 3] = [255, 255, 255];
+/// Colour of the cell-edge overlay
+static EDGE: [u8; 3] = [0, 0, 0];
+/// Characters of the ASCII heatmap, from the lowest value to the highest
+static ASCII_RAMP: &[u8] = b" .:-=+*#%@";
+
+/// Colormap anchors at nine evenly spaced points
+static VIRIDIS: [[u8; 3]; 9] = [
+    [68, 1, 84], [71, 45, 123], [59, 82, 139], [44, 114, 142], [33, 145, 140],
+    [40, 174, 128], [94, 201, 98], [173, 220, 48], [253, 231, 37],
+];
+static INFERNO: [[u8; 3]; 9] = [
+    [0, 0, 4], [31, 12, 72], [85, 15, 109], [136, 34, 106], [186, 54, 85],
+    [227, 89, 51], [249, 142, 9], [249, 203, 53], [252, 255, 164],
+];
+static DIVERGING: [[u8; 3]; 9] = [
+    [59, 76, 192], [98, 130, 234], [141, 176, 254], [184, 208, 249], [221, 221, 221],
+    [245, 196, 173], [244, 154, 123], [222, 96, 77], [180, 4, 38],
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Colormap {
+    Viridis,
+    Inferno,
+    /// Blue through grey to red, for values on either side of a midpoint
+    Diverging,
+}
+
+impl Colormap {
+    /// Colour of `t` in `[0, 1]`; values outside are clamped
+    pub fn color(self: &Colormap, t: f64) -> [u8; 3] {
+        let anchors = match self {
+            Colormap::Viridis => &VIRIDIS,
+            Colormap::Inferno => &INFERNO,
+            Colormap::Diverging => &DIVERGING,
+        };
+        let x = if t.is_nan() { 0.0 } else { t.clamp(0.0, 1.0) } * (anchors.len() - 1) as f64;
+        let i = (x.floor() as usize).min(anchors.len() - 2);
+        let f = x - i as f64;
+        let mut rgb = [0; 3];
+        for (c, value) in rgb.iter_mut().enumerate() {
+            let (a, b) = (anchors[i][c] as f64, anchors[i + 1][c] as f64);
+            *value = (a + f * (b - a)).round() as u8;
+        }
+        rgb
+    }
+}
+
+/// Robinson's table of parallel lengths and distances from the equator at every 5 degrees of latitude
+static ROBINSON_X: [f64; 19] = [
+    1.0000, 0.9986, 0.9954, 0.9900, 0.9822, 0.9730, 0.9600, 0.9427, 0.9216, 0.8962,
+    0.8679, 0.8350, 0.7986, 0.7597, 0.7186, 0.6732, 0.6213, 0.5722, 0.5322,
+];
+static ROBINSON_Y: [f64; 19] = [
+    0.0000, 0.0620, 0.1240, 0.1860, 0.2480, 0.3100, 0.3720, 0.4340, 0.4958, 0.5571,
+    0.6176, 0.6769, 0.7346, 0.7903, 0.8435, 0.8936, 0.9394, 0.9761, 1.0000,
+];
+
+/// Longest piece of a projected cell outline, in radians, before it is subdivided
+static MAX_SEGMENT: f64 = PI / 180.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Projection {
+    /// Plate carrée, longitude -180 at the left and latitude 90 at the top
+    Equirectangular,
+    /// The hemisphere seen from far above the given point
+    Orthographic(coords::Coordinate),
+    Mollweide,
+    Robinson,
+    HammerAitoff,
+}
+
+impl Projection {
+    /// Half the width and half the height of the whole map in projected units
+    fn extent(self: &Projection) -> (f64, f64) {
+        match self {
+            Projection::Equirectangular => (PI, FRAC_PI_2),
+            Projection::Orthographic(_) => (1.0, 1.0),
+            Projection::Mollweide | Projection::HammerAitoff => (2.0 * SQRT_2, SQRT_2),
+            Projection::Robinson => (0.8487 * PI, 1.3523),
+        }
+    }
+    /// Projected position of longitude `lon` in `[-pi, pi]` and latitude `lat`.
+    ///
+    /// Orthographic maps are projected from cartesian points instead, since
+    /// they have to be clipped to the visible hemisphere first.
+    fn forward(self: &Projection, lon: f64, lat: f64) -> (f64, f64) {
+        match self {
+            Projection::Equirectangular | Projection::Orthographic(_) => (lon, lat),
+            Projection::Mollweide => {
+                // Solve 2t + sin(2t) = pi sin(lat) for the auxiliary angle t
+                let target = PI * lat.sin();
+                let mut t = lat;
+                for _ in 0..50 {
+                    let slope = 2.0 + 2.0 * (2.0 * t).cos();
+                    if slope < 1e-12 { break; }
+                    let step = (2.0 * t + (2.0 * t).sin() - target) / slope;
+                    t -= step;
+                    if step.abs() < 1e-12 { break; }
+                }
+                (2.0 * SQRT_2 / PI * lon * t.cos(), SQRT_2 * t.sin())
+            },
+            Projection::Robinson => {
+                let x = (lat.abs().to_degrees() / 5.0).min(18.0);
+                let i = (x.floor() as usize).min(17);
+                let f = x - i as f64;
+                let length = ROBINSON_X[i] + f * (ROBINSON_X[i + 1] - ROBINSON_X[i]);
+                let distance = ROBINSON_Y[i] + f * (ROBINSON_Y[i + 1] - ROBINSON_Y[i]);
+                (0.8487 * length * lon, 1.3523 * distance * lat.signum())
+            },
+            Projection::HammerAitoff => {
+                let z = (1.0 + lat.cos() * (lon / 2.0).cos()).sqrt();
+                (2.0 * SQRT_2 * lat.cos() * (lon / 2.0).sin() / z, SQRT_2 * lat.sin() / z)
+            },
+        }
+    }
+}
+
+/// How to draw a map
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MapOptions {
+    /// Image width in pixels; the height follows from the projection
+    pub width: u32,
+    pub projection: Projection,
+    pub colormap: Colormap,
+    /// Draw the cell edges over the values
+    pub edges: bool,
+    /// Values mapped to the ends of the colormap, or the range of the mesh if `None`
+    pub range: Option<(f64, f64)>,
+}
+
+impl Default for MapOptions {
+    fn default() -> MapOptions {
+        MapOptions { width: 720, projection: Projection::Equirectangular, colormap: Colormap::Viridis, edges: false, range: None }
+    }
+}
+
+/// An RGB image, stored row by row from the top
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<[u8; 3]>,
+}
+
+impl Image {
+    /// Write the image as a binary PPM (P6) file
+    pub fn write_ppm(self: &Image, path: &Path) -> std::io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        write!(out, "P6\n{} {}\n255\n", self.width, self.height)?;
+        out.write_all(self.pixels.as_flattened())?;
+        out.flush()
+    }
+    #[cfg(feature = "png")]
+    pub fn write_png(self: &Image, path: &Path) -> std::io::Result<()> {
+        let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), self.width, self.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(std::io::Error::other)?;
+        writer.write_image_data(self.pixels.as_flattened()).map_err(std::io::Error::other)?;
+        writer.finish().map_err(std::io::Error::other)
+    }
+    /// Write a `.png` or `.ppm` file, chosen by extension
+    pub fn write(self: &Image, path: &Path) -> std::io::Result<()> {
+        match path.extension().and_then(|e| e.to_str()) {
+            #[cfg(feature = "png")]
+            Some("png") => self.write_png(path),
+            #[cfg(not(feature = "png"))]
+            Some("png") => Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "built without the png feature")),
+            Some("ppm") => self.write_ppm(path),
+            _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "unknown image format; expected .png or .ppm")),
+        }
+    }
+}
+
+/// Index of the cell under each pixel of a `width` by `height`
+/// equirectangular grid, from longitude -180 and latitude 90 at the top left
+fn sample_equirectangular(network: &grid::GridNetwork, width: u32, height: u32) -> Result<Vec<Option<usize>>, Error> {
+    let locator = grid::CellLocator::new(network)?;
+    let mut cells = Vec::with_capacity((width * height) as usize);
+    let mut hint = None;
+    for row in 0..height {
+        let theta = (row as f64 + 0.5) / height as f64 * PI;
+        for col in 0..width {
+            let phi = (col as f64 + 0.5) / width as f64 * 2.0 * PI - PI;
+            let cell = locator.locate(&coords::Coordinate::new(phi, theta)?, hint)?;
+            hint = cell.or(hint);
+            cells.push(cell);
+        }
+    }
+    Ok(cells)
+}
+
+type Vector = [f64; 3];
+
+fn dot(a: Vector, b: Vector) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: Vector, b: Vector) -> Vector {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+fn normalized(a: Vector) -> Vector {
+    let mag = dot(a, a).sqrt();
+    [a[0] / mag, a[1] / mag, a[2] / mag]
+}
+
+/// Corners of `polygon` as unit vectors, with points added along each
+/// great-circle edge so that no piece is longer than `MAX_SEGMENT`
+fn outline(polygon: &coords::Polygon) -> Result<Vec<Vector>, Error> {
+    let nodes = polygon.nodes.iter().map(|n| n.cart().map(|(x, y, z)| [x, y, z])).collect::<Result<Vec<Vector>, Error>>()?;
+    let mut points = Vec::new();
+    for (i, &a) in nodes.iter().enumerate() {
+        let b = nodes[(i + 1) % nodes.len()];
+        let angle = dot(a, b).clamp(-1.0, 1.0).acos();
+        let n = (angle / MAX_SEGMENT).ceil().max(1.0) as usize;
+        points.push(a);
+        for k in 1..n {
+            let t = k as f64 / n as f64;
+            let (wa, wb) = (((1.0 - t) * angle).sin(), (t * angle).sin());
+            points.push(normalized([wa * a[0] + wb * b[0], wa * a[1] + wb * b[1], wa * a[2] + wb * b[2]]));
+        }
+    }
+    Ok(points)
+}
+
+/// Sutherland-Hodgman clipping of a closed polygon to the side where `distance` is not negative
+fn clip<P: Copy>(points: &[P], distance: impl Fn(&P) -> f64, lerp: impl Fn(&P, &P, f64) -> P) -> Vec<P> {
+    let mut clipped = Vec::with_capacity(points.len() + 2);
+    for (i, p) in points.iter().enumerate() {
+        let q = &points[(i + 1) % points.len()];
+        let (dp, dq) = (distance(p), distance(q));
+        if dp >= 0.0 {
+            clipped.push(*p);
+        }
+        if (dp >= 0.0) != (dq >= 0.0) {
+            clipped.push(lerp(p, q, dp / (dp - dq)));
+        }
+    }
+    clipped
+}
+
+/// Pieces of a cell outline in longitude and latitude, cut at the
+/// antimeridian, with cells around a pole closed along the pole
+fn lonlat_pieces(points: &[Vector]) -> Result<Vec<Vec<(f64, f64)>>, Error> {
+    let wrap = |x: f64| (x + PI).rem_euclid(2.0 * PI) - PI;
+    let mut raw: Vec<(Option<f64>, f64)> = Vec::with_capacity(points.len());
+    for p in points.iter() {
+        let c = coords::Coordinate::from_cart(p[0], p[1], p[2])?;
+        raw.push(((p[0].hypot(p[1]) > 1e-12).then_some(c.phi), FRAC_PI_2 - c.theta));
+    }
+    if raw.iter().all(|(lon, _)| lon.is_none()) {
+        return Ok(Vec::new());
+    }
+    // A corner on a pole has no longitude of its own; it becomes a stretch of
+    // the pole between the longitudes of the points before and after it
+    let n = raw.len();
+    let known = |start: usize, step: usize| (1..n).map(|k| raw[(start + k * step) % n].0).find_map(|lon| lon).expect("a point off the pole");
+    let mut lonlat: Vec<(f64, f64)> = Vec::with_capacity(n + 3);
+    for (i, &(lon, lat)) in raw.iter().enumerate() {
+        let lons = match lon {
+            Some(lon) => vec![lon],
+            None => vec![known(i, n - 1), known(i, 1)],
+        };
+        for lon in lons {
+            let lon = match lonlat.last() {
+                Some(&(last, _)) => last + wrap(lon - last),
+                None => lon,
+            };
+            lonlat.push((lon, lat));
+        }
+    }
+    let (first, last) = (lonlat[0], lonlat[lonlat.len() - 1]);
+    let winding = last.0 + wrap(first.0 - last.0) - first.0;
+    if winding.abs() > PI {
+        // The outline goes once around a pole; close it along the pole instead
+        let z: f64 = points.iter().map(|p| p[2]).sum();
+        let pole = FRAC_PI_2.copysign(z);
+        lonlat.extend([(first.0 + winding, first.1), (first.0 + winding, pole), (first.0, pole)]);
+    }
+    let lerp = |p: &(f64, f64), q: &(f64, f64), t: f64| (p.0 + t * (q.0 - p.0), p.1 + t * (q.1 - p.1));
+    let mut pieces = Vec::new();
+    for shift in [-2.0 * PI, 0.0, 2.0 * PI] {
+        let shifted: Vec<(f64, f64)> = lonlat.iter().map(|&(lon, lat)| (lon + shift, lat)).collect();
+        let piece = clip(&clip(&shifted, |p| p.0 + PI, lerp), |p| PI - p.0, lerp);
+        if piece.len() < 3 { continue; }
+        // Cuts along the antimeridian and the poles are straight only in longitude and latitude
+        let mut dense = Vec::with_capacity(piece.len());
+        for (i, p) in piece.iter().enumerate() {
+            let q = &piece[(i + 1) % piece.len()];
+            let n = ((q.0 - p.0).hypot(q.1 - p.1) / MAX_SEGMENT).ceil().max(1.0) as usize;
+            dense.extend((0..n).map(|k| lerp(p, q, k as f64 / n as f64)));
+        }
+        pieces.push(dense);
+    }
+    Ok(pieces)
+}
+
+/// The part of a cell outline on the visible side of an orthographic view,
+/// as `(x, y)` on the unit disk
+fn orthographic_piece(points: &[Vector], basis: &[Vector; 3]) -> Vec<(f64, f64)> {
+    let [east, north, forward] = *basis;
+    let view: Vec<Vector> = points.iter().map(|&p| [dot(p, east), dot(p, north), dot(p, forward)]).collect();
+    let lerp = |p: &Vector, q: &Vector, t: f64| [p[0] + t * (q[0] - p[0]), p[1] + t * (q[1] - p[1]), p[2] + t * (q[2] - p[2])];
+    let visible = clip(&view, |p| p[2], lerp);
+    let mut piece = Vec::with_capacity(visible.len());
+    for (i, p) in visible.iter().enumerate() {
+        let q = &visible[(i + 1) % visible.len()];
+        piece.push((p[0], p[1]));
+        // Follow the horizon between two points on it rather than cutting across the disk
+        if p[2].abs() < 1e-9 && q[2].abs() < 1e-9 {
+            let (a, b) = (p[1].atan2(p[0]), q[1].atan2(q[0]));
+            let sweep = (b - a + PI).rem_euclid(2.0 * PI) - PI;
+            let n = (sweep.abs() / MAX_SEGMENT).ceil() as usize;
+            piece.extend((1..n).map(|k| {
+                let angle = a + sweep * k as f64 / n as f64;
+                (angle.cos(), angle.sin())
+            }));
+        }
+    }
+    piece
+}
+
+/// East, north and towards-the-viewer unit vectors of an orthographic view of `center`.
+///
+/// Seen from above a pole, longitude 0 points down from the north pole and
+/// up from the south pole.
+fn orthographic_basis(center: &coords::Coordinate) -> Result<[Vector; 3], Error> {
+    let (x, y, z) = center.cart()?;
+    let forward = [x, y, z];
+    let up = if x.hypot(y) < 1e-9 { [-z.signum(), 0.0, 0.0] } else { [0.0, 0.0, 1.0] };
+    let d = dot(up, forward);
+    let north = normalized([up[0] - d * x, up[1] - d * y, up[2] - d * z]);
+    Ok([cross(north, forward), north, forward])
+}
+
+/// Set every pixel whose center is inside `piece`, given in pixel coordinates, to `cell`
+fn fill(cells: &mut [Option<usize>], width: u32, height: u32, piece: &[(f64, f64)], cell: usize) {
+    let (ymin, ymax) = piece.iter().fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), p| (lo.min(p.1), hi.max(p.1)));
+    let first = (ymin - 0.5).ceil().max(0.0);
+    let last = (ymax - 0.5).floor().min(height as f64 - 1.0);
+    if last < first {
+        return;
+    }
+    let mut crossings: Vec<f64> = Vec::new();
+    for row in first as u32..=last as u32 {
+        let y = row as f64 + 0.5;
+        crossings.clear();
+        for (i, p) in piece.iter().enumerate() {
+            let q = &piece[(i + 1) % piece.len()];
+            if (p.1 <= y) != (q.1 <= y) {
+                crossings.push(p.0 + (y - p.1) / (q.1 - p.1) * (q.0 - p.0));
+            }
+        }
+        crossings.sort_by(f64::total_cmp);
+        for span in crossings.chunks_exact(2) {
+            let start = (span[0] - 0.5).ceil().max(0.0) as u32;
+            let end = ((span[1] - 0.5).ceil().min(width as f64)).max(0.0) as u32;
+            for col in start..end {
+                cells[(row * width + col) as usize] = Some(cell);
+            }
+        }
+    }
+}
+
+/// Index of the cell under each pixel of a `width` by `height` map in `projection`
+fn rasterize(network: &grid::GridNetwork, projection: &Projection, width: u32, height: u32) -> Result<Vec<Option<usize>>, Error> {
+    let (half_width, half_height) = projection.extent();
+    let to_pixel = |(x, y): (f64, f64)| ((x + half_width) / (2.0 * half_width) * width as f64, (half_height - y) / (2.0 * half_height) * height as f64);
+    let basis = match projection {
+        Projection::Orthographic(center) => Some(orthographic_basis(center)?),
+        _ => None,
+    };
+    let mut cells = vec![None; (width * height) as usize];
+    for (i, cell) in network.cells.iter().enumerate() {
+        let points = outline(&cell.polygon)?;
+        let pieces = match &basis {
+            Some(basis) => vec![orthographic_piece(&points, basis)],
+            None => lonlat_pieces(&points)?.into_iter()
+                .map(|piece| piece.into_iter().map(|(lon, lat)| projection.forward(lon.clamp(-PI, PI), lat)).collect())
+                .collect(),
+        };
+        for piece in pieces.iter().filter(|p| p.len() >= 3) {
+            let piece: Vec<(f64, f64)> = piece.iter().map(|&p| to_pixel(p)).collect();
+            fill(&mut cells, width, height, &piece, i);
+        }
+    }
+    Ok(cells)
+}
+
+/// Cell values scaled to `[0, 1]` over `range`, or over the range of the mesh
+fn scaled_values(network: &grid::GridNetwork, range: Option<(f64, f64)>) -> Vec<f64> {
+    let (min, max) = range.unwrap_or((network.min_value(), network.max_value()));
+    network.cells.iter().map(|c| if max > min { (c.value - min) / (max - min) } else { 0.5 }).collect()
+}
+
+/// Draw `network` on a map
+pub fn draw(network: &grid::GridNetwork, options: &MapOptions) -> Result<Image, Error> {
+    if options.width < 2 {
+        return Err(Error::InvalidArgument("A map must be at least two pixels wide"));
+    }
+    let (half_width, half_height) = options.projection.extent();
+    let width = options.width;
+    let height = ((width as f64 * half_height / half_width).round() as u32).max(1);
+    let cells = match options.projection {
+        Projection::Equirectangular => sample_equirectangular(network, width, height)?,
+        projection => rasterize(network, &projection, width, height)?,
+    };
+    let values = scaled_values(network, options.range);
+    let w = width as usize;
+    let pixels = cells.iter().enumerate().map(|(k, cell)| {
+        let Some(cell) = cell else { return BACKGROUND };
+        // A pixel is on an edge when the pixel right of or below it is in another cell
+        let right = (k % w + 1 < w).then(|| cells[k + 1]);
+        let below = cells.get(k + w).copied();
+        if options.edges && [right, below].iter().flatten().any(|other| *other != Some(*cell)) {
+            return EDGE;
+        }
+        options.colormap.color(values[*cell])
+    }).collect();
+    Ok(Image { width, height, pixels })
+}
+
+/// Equirectangular heatmap of `network` in characters, `width` columns wide,
+/// followed by a line giving the value range the characters span
+pub fn ascii_map(network: &grid::GridNetwork, width: u32) -> Result<String, Error> {
+    if width < 4 {
+        return Err(Error::InvalidArgument("An ASCII map must be at least four columns wide"));
+    }
+    // Terminal characters are about twice as tall as they are wide
+    let height = width / 4;
+    let cells = sample_equirectangular(network, width, height)?;
+    let values = scaled_values(network, None);
+    let mut text = String::new();
+    for row in cells.chunks(width as usize) {
+        for cell in row {
+            text.push(match cell {
+                Some(cell) => {
+                    let i = (values[*cell] * (ASCII_RAMP.len() - 1) as f64).round() as usize;
+                    ASCII_RAMP[i.min(ASCII_RAMP.len() - 1)] as char
+                },
+                None => '?',
+            });
+        }
+        text.push('\n');
+    }
+    text.push_str(&format!("{} \"{}\" {}\n", network.min_value(), std::str::from_utf8(ASCII_RAMP).expect("ASCII"), network.max_value()));
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meshgen::MeshType;
+    use crate::pgen;
+
+    #[test]
+    fn test_locate_and_draw() {
+        let network = pgen::init_mesh(MeshType::Geodesic(2), pgen::InitialCondition::Radiative).unwrap();
+        let locator = grid::CellLocator::new(&network).unwrap();
+        for (i, cell) in network.cells.iter().enumerate() {
+            assert_eq!(locator.locate(&cell.center(), Some(0)).unwrap(), Some(i));
+        }
+
+        let options = MapOptions { width: 64, edges: true, ..Default::default() };
+        let image = draw(&network, &options).unwrap();
+        assert_eq!((image.width, image.height, image.pixels.len()), (64, 32, 64 * 32));
+        assert!(!image.pixels.contains(&BACKGROUND));
+        assert!(image.pixels.contains(&EDGE));
+        assert_eq!(Colormap::Viridis.color(0.0), VIRIDIS[0]);
+        assert_eq!(Colormap::Inferno.color(1.0), INFERNO[8]);
+
+        let path = std::env::temp_dir().join(format!("isosphere_render_{}.ppm", std::process::id()));
+        image.write(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        assert!(bytes.starts_with(b"P6\n64 32\n255\n"));
+        assert_eq!(bytes.len(), "P6\n64 32\n255\n".len() + 64 * 32 * 3);
+        std::fs::remove_file(&path).unwrap();
+
+        let text = ascii_map(&network, 40).unwrap();
+        assert_eq!(text.lines().count(), 11);
+        assert!(text.lines().take(10).all(|l| l.len() == 40 && !l.contains('?')));
+    }
+
+    #[test]
+    fn test_projections_cover_the_globe() {
+        let network = pgen::init_mesh(MeshType::LatLon(9, 18), pgen::InitialCondition::Radiative).unwrap();
+        let pole = coords::Coordinate::new(0.0, 0.0).unwrap();
+        for projection in [Projection::Orthographic(pole), Projection::Mollweide, Projection::Robinson, Projection::HammerAitoff] {
+            let options = MapOptions { width: 200, projection, ..Default::default() };
+            let image = draw(&network, &options).unwrap();
+            let (half_width, half_height) = projection.extent();
+            // Every pixel well inside the outline of the map belongs to a cell
+            for (k, pixel) in image.pixels.iter().enumerate() {
+                let x = ((k as u32 % image.width) as f64 + 0.5) / image.width as f64 * 2.0 - 1.0;
+                let y = ((k as u32 / image.width) as f64 + 0.5) / image.height as f64 * 2.0 - 1.0;
+                let inside = match projection {
+                    Projection::Robinson => {
+                        let (edge, _) = projection.forward(PI, (y * half_height / 1.3523).clamp(-1.0, 1.0) * FRAC_PI_2);
+                        x.abs() < 0.95 * edge / half_width && y.abs() < 0.95
+                    },
+                    _ => x.hypot(y) < 0.95,
+                };
+                assert!(!inside || *pixel != BACKGROUND, "{:?} leaves a gap at pixel {}", projection, k);
+            }
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Sparse linear algebra on the mesh adjacency
+//!
+//!
+
+use super::grid;
+use super::error::Error;
+
+/// A square matrix whose off-diagonal entries follow the cell adjacency of a mesh.
+///
+/// Row `i` stores its diagonal separately and one entry for each of
+/// `topology.neighbors[i]`, in the same order.
+#[derive(Clone)]
+pub struct SparseMatrix {
+    pub diag: Vec<f64>,
+    pub cols: Vec<Vec<usize>>,
+    pub vals: Vec<Vec<f64>>,
+}
+
+impl SparseMatrix {
+    /// Zero matrix with the sparsity pattern of `topology`
+    pub fn from_topology(topology: &grid::MeshTopology) -> SparseMatrix {
+        let cols = topology.neighbors.clone();
+        let vals = cols.iter().map(|c| vec![0.0; c.len()]).collect();
+        SparseMatrix { diag: vec![0.0; cols.len()], cols, vals }
+    }
+    pub fn len(self: &SparseMatrix) -> usize {
+        self.diag.len()
+    }
+    pub fn is_empty(self: &SparseMatrix) -> bool {
+        self.diag.is_empty()
+    }
+    /// Add `v` to the entry at row `i`, column `j`
+    pub fn add(self: &mut SparseMatrix, i: usize, j: usize, v: f64) -> Result<(), Error> {
+        if i == j {
+            self.diag[i] += v;
+            return Ok(());
+        }
+        match self.cols[i].iter().position(|&c| c == j) {
+            Some(k) => { self.vals[i][k] += v; Ok(()) },
+            None => Err(Error::InvalidArgument("Entry is outside the sparsity pattern"))
+        }
+    }
+    pub fn matvec(self: &SparseMatrix, x: &[f64]) -> Vec<f64> {
+        (0..self.len()).map(|i| {
+            let mut sum = self.diag[i] * x[i];
+            for (&j, &v) in self.cols[i].iter().zip(self.vals[i].iter()) {
+                sum += v * x[j];
+            }
+            sum
+        }).collect()
+    }
+    /// Solve `self * x = b` by Jacobi-preconditioned BiCGSTAB.
+    ///
+    /// Iterates until the residual norm is below `tol * |b|`.
+    pub fn solve(self: &SparseMatrix, b: &[f64], x0: &[f64], tol: f64, max_iter: usize) -> Result<Vec<f64>, Error> {
+        if self.diag.contains(&0.0) {
+            return Err(Error::SolverFailure{solver: "BiCGSTAB", reason: "zero on the diagonal"});
+        }
+        let precondition = |v: &[f64]| -> Vec<f64> { v.iter().zip(self.diag.iter()).map(|(a, d)| a / d).collect() };
+        let b_norm = norm(b);
+        let mut x = x0.to_vec();
+        let ax = self.matvec(&x);
+        let mut r: Vec<f64> = b.iter().zip(ax.iter()).map(|(b, a)| b - a).collect();
+        if b_norm == 0.0 || norm(&r) <= tol * b_norm {
+            return Ok(x);
+        }
+        let r_hat = r.clone();
+        let mut rho = 1.0;
+        let mut alpha = 1.0;
+        let mut omega = 1.0;
+        let mut v = vec![0.0; b.len()];
+        let mut p = vec![0.0; b.len()];
+        for _ in 0..max_iter {
+            let rho_next = dot(&r_hat, &r);
+            if rho_next == 0.0 {
+                return Err(Error::SolverFailure{solver: "BiCGSTAB", reason: "broke down"});
+            }
+            let beta = rho_next / rho * alpha / omega;
+            rho = rho_next;
+            for k in 0..p.len() {
+                p[k] = r[k] + beta * (p[k] - omega * v[k]);
+            }
+            let p_hat = precondition(&p);
+            v = self.matvec(&p_hat);
+            alpha = rho / dot(&r_hat, &v);
+            let s: Vec<f64> = r.iter().zip(v.iter()).map(|(r, v)| r - alpha * v).collect();
+            if norm(&s) <= tol * b_norm {
+                for k in 0..x.len() {
+                    x[k] += alpha * p_hat[k];
+                }
+                return Ok(x);
+            }
+            let s_hat = precondition(&s);
+            let t = self.matvec(&s_hat);
+            omega = dot(&t, &s) / dot(&t, &t);
+            for k in 0..x.len() {
+                x[k] += alpha * p_hat[k] + omega * s_hat[k];
+                r[k] = s[k] - omega * t[k];
+            }
+            if norm(&r) <= tol * b_norm {
+                return Ok(x);
+            }
+            if omega == 0.0 {
+                return Err(Error::SolverFailure{solver: "BiCGSTAB", reason: "broke down"});
+            }
+        }
+        Err(Error::SolverFailure{solver: "BiCGSTAB", reason: "did not converge"})
+    }
+}
+
+pub fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b.iter()).map(|(a, b)| a * b).sum()
+}
+
+pub fn norm(a: &[f64]) -> f64 {
+    dot(a, a).sqrt()
+}
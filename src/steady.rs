@@ -0,0 +1,112 @@
+//! Steady-state solver
+//!
+//! Finds the temperature map where incident, thermal, advective and
+//! diffusive fluxes balance in every cell, by pseudo-transient continuation:
+//! backward Euler steps whose size grows as the residual falls.
+
+use log::{info,warn};
+
+use super::{forcing, grid, integrate, pgen, sparse};
+use super::error::Error;
+
+/// Pseudo-timestep the continuation starts from
+static INITIAL_DT: f64 = 0.1;
+/// Largest pseudo-timestep; keeps the Newton systems well conditioned on the night side
+static MAX_DT: f64 = 1e8;
+/// Smallest pseudo-timestep before giving up
+static MIN_DT: f64 = 1e-8;
+
+pub struct SteadyState {
+    pub network: grid::GridNetwork,
+    /// Relative residual norm `|A dT/dt| / |incident flux|` of the final state,
+    /// or `|A dT/dt|` if no starlight is absorbed
+    pub residual: f64,
+    pub iterations: usize,
+    pub balance: pgen::EnergyBalance
+}
+
+/// Norm of the starlight absorbed by every cell
+fn incident_norm(network: &grid::GridNetwork, light: &forcing::Illumination) -> f64 {
+    sparse::norm(&network.cells.iter().map(|c| pgen::incident_flux(c,light)).collect::<Vec<f64>>())
+}
+
+/// Net flux into every cell relative to the total incident flux, or the
+/// absolute net flux if there is no incident flux to compare it with
+pub fn residual_norm(network: &grid::GridNetwork, eps1: f64, eps2: f64, light: &forcing::Illumination) -> Result<f64,Error> {
+    let tendency = integrate::tendency(network,eps1,eps2,light)?;
+    let net: Vec<f64> = network.cells.iter().zip(tendency.iter()).map(|(c, t)| c.surface.heat_capacity * c.polygon.area() * t).collect();
+    let incident = incident_norm(network,light);
+    if incident > 0.0 { Ok(sparse::norm(&net) / incident) } else { Ok(sparse::norm(&net)) }
+}
+
+/// Solve `incident - thermal - advective + diffusive = 0` for every cell.
+///
+/// Converged once the relative residual is below `tolerance` and
+/// `pgen::check_energy_balance` reports the mesh as balanced, which it cannot
+/// without starlight, or once the residual is exactly zero.
+pub fn solve(network: grid::GridNetwork, eps1: f64, eps2: f64, light: &forcing::Illumination, tolerance: f64, max_iterations: usize) -> Result<SteadyState,Error> {
+    let mut network = network;
+    let mut dt = INITIAL_DT;
+    let mut residual = residual_norm(&network,eps1,eps2,light)?;
+    for iteration in 0..max_iterations {
+        if residual < tolerance || residual == 0.0 {
+            let balance = pgen::check_energy_balance(&network,light);
+            let dark = incident_norm(&network,light) == 0.0;
+            if residual == 0.0 || dark || matches!(balance, pgen::EnergyBalance::Balanced(_)) {
+                info!("Steady state reached after {} iterations with residual {}",iteration,residual);
+                return Ok(SteadyState{network, residual, iterations: iteration, balance});
+            }
+        }
+        match integrate::step(&network,eps1,eps2,light,dt,integrate::TimeScheme::BackwardEuler) {
+            Ok(next) => {
+                let next_residual = residual_norm(&next,eps1,eps2,light)?;
+                info!("Pseudo-timestep {}: dt = {}, residual = {}",iteration,dt,next_residual);
+                // Switched evolution relaxation: grow dt as fast as the residual shrinks.
+                // A zero residual is converged, and checked for above.
+                if next_residual > 0.0 {
+                    dt = (dt * residual / next_residual).clamp(dt / 2.0, MAX_DT);
+                }
+                network = next;
+                residual = next_residual;
+            },
+            Err(e) => {
+                warn!("Pseudo-timestep {} failed with dt = {}: {}",iteration,dt,e);
+                dt /= 2.0;
+                if dt < MIN_DT {
+                    return Err(Error::SolverFailure{solver: "Steady-state solver", reason: "pseudo-timestep became too small"});
+                }
+            }
+        }
+    }
+    Err(Error::SolverFailure{solver: "Steady-state solver", reason: "did not converge"})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meshgen::MeshType;
+
+    #[test]
+    fn test_steady_state() {
+        let network = pgen::init_mesh(MeshType::Geodesic(2), pgen::InitialCondition::Radiative).unwrap();
+        let light = forcing::Illumination::default();
+        let steady = solve(network, 1.0, 1.0, &light, 1e-9, 100).unwrap();
+        assert!(steady.residual < 1e-9);
+        assert!(matches!(steady.balance, pgen::EnergyBalance::Balanced(_)));
+        // A further explicit step barely moves the solution
+        let dt = pgen::cfl_timestep(&steady.network, pgen::COURANT_NUMBER, pgen::LengthScale::MeanCellSize, 1.0, 1.0).dt();
+        let next = integrate::step(&steady.network, 1.0, 1.0, &light, dt, integrate::TimeScheme::ForwardEuler).unwrap();
+        for (a, b) in steady.network.cells.iter().zip(next.cells.iter()) {
+            assert!((a.value - b.value).abs() < 1e-8);
+        }
+
+        // Without starlight the residual is absolute, and a cold planet is already steady
+        let dark = forcing::Illumination{scale: 0.0, ..light};
+        let warm = pgen::init_mesh(MeshType::Geodesic(1), pgen::InitialCondition::Constant(0.5)).unwrap();
+        let steady = solve(warm, 1.0, 1.0, &dark, 1e-6, 100).unwrap();
+        assert!(steady.residual < 1e-6);
+        let cold = pgen::init_mesh(MeshType::Geodesic(1), pgen::InitialCondition::Constant(0.0)).unwrap();
+        let steady = solve(cold, 1.0, 1.0, &dark, 0.0, 100).unwrap();
+        assert_eq!((steady.residual, steady.iterations), (0.0, 0));
+    }
+}
@@ -0,0 +1,243 @@
+//! Surface properties of the cells
+//!
+//! Every cell absorbs `1 - albedo` of the starlight falling on it, emits
+//! `emissivity` times the black-body flux and stores heat with
+//! `heat_capacity`. The defaults of 0, 1 and 1 give the original uniform
+//! problem. Properties are set from a function of latitude and longitude,
+//! or from a map file sampled at points, to describe continents, ice caps
+//! and clouds.
+
+use std::fs;
+use std::path::Path;
+
+use super::{coords, grid};
+use super::error::Error;
+
+/// Temperature-dependent albedo of a surface that freezes over
+#[derive(Clone,Copy,Debug,PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(deny_unknown_fields))]
+pub struct IceAlbedo {
+    /// Albedo of a cell covered in ice
+    pub albedo: f64,
+    /// Value at which a cell is half frozen
+    pub freeze: f64,
+    /// Width of the `tanh` ramp from open surface to ice; 0 switches abruptly, as in Budyko's model
+    pub width: f64
+}
+
+impl IceAlbedo {
+    /// Ice cover of a cell holding `value`, from 0 for open surface to 1 for ice
+    pub fn ice_fraction(self:&IceAlbedo, value: f64) -> f64 {
+        if self.width == 0.0 {
+            if value < self.freeze { 1.0 } else { 0.0 }
+        }
+        else { 0.5 * (1.0 - ((value - self.freeze) / self.width).tanh()) }
+    }
+    pub fn check(self:&IceAlbedo) -> Result<(),Error> {
+        if !(0.0..=1.0).contains(&self.albedo) {
+            return Err(Error::InvalidArgument("ice albedo must be between 0 and 1"));
+        }
+        if !(self.freeze.is_finite() && self.width.is_finite() && self.width >= 0.0) {
+            return Err(Error::InvalidArgument("freezing point must be finite and the ramp width finite and not negative"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Surface {
+    /// Fraction of the incident starlight that is reflected, over open surface if the cell can freeze
+    pub albedo: f64,
+    /// Thermal emission relative to a black body
+    pub emissivity: f64,
+    /// Energy per unit area needed to raise the value by one
+    pub heat_capacity: f64,
+    /// Ice-albedo feedback, if the cell can freeze
+    pub ice: Option<IceAlbedo>
+}
+
+impl Default for Surface {
+    fn default() -> Surface {
+        Surface{albedo: 0.0, emissivity: 1.0, heat_capacity: 1.0, ice: None}
+    }
+}
+
+impl Surface {
+    /// Albedo of the cell when it holds `value`
+    pub fn albedo_at(self:&Surface, value: f64) -> f64 {
+        match self.ice {
+            None => self.albedo,
+            Some(ice) => {
+                let frozen = ice.ice_fraction(value);
+                (1.0 - frozen) * self.albedo + frozen * ice.albedo
+            }
+        }
+    }
+    /// Ice cover of the cell when it holds `value`; 0 for a cell that cannot freeze
+    pub fn ice_fraction(self:&Surface, value: f64) -> f64 {
+        self.ice.map_or(0.0, |ice| ice.ice_fraction(value))
+    }
+    pub fn check(self:&Surface) -> Result<(),Error> {
+        if let Some(ice) = &self.ice {
+            ice.check()?;
+        }
+        if !(0.0..=1.0).contains(&self.albedo) {
+            return Err(Error::InvalidArgument("albedo must be between 0 and 1"));
+        }
+        if !(0.0..=1.0).contains(&self.emissivity) {
+            return Err(Error::InvalidArgument("emissivity must be between 0 and 1"));
+        }
+        if self.heat_capacity.is_nan() || self.heat_capacity <= 0.0 {
+            return Err(Error::InvalidArgument("heat capacity must be positive"));
+        }
+        Ok(())
+    }
+}
+
+/// Latitude and longitude in radians of the centroid of `cell`
+fn lat_lon(cell: &grid::GridCell) -> (f64,f64) {
+    let center = cell.center();
+    (std::f64::consts::FRAC_PI_2 - center.theta, center.phi)
+}
+
+/// Change the surface of every cell by `f(lat, lon, surface)`, with the
+/// latitude and longitude of its centroid in radians
+pub fn apply(network: &mut grid::GridNetwork, f: impl Fn(f64,f64,&mut Surface)) -> Result<(),Error> {
+    for cell in network.cells.iter_mut() {
+        let (lat, lon) = lat_lon(cell);
+        let mut surface = cell.surface;
+        f(lat, lon, &mut surface);
+        surface.check()?;
+        cell.surface = surface;
+    }
+    Ok(())
+}
+
+/// Surface properties sampled at points, read by `read_map`.
+///
+/// A property without a column leaves the cells as they were.
+#[derive(Clone,Debug,Default)]
+pub struct SurfaceMap {
+    pub points: Vec<coords::Coordinate>,
+    pub albedo: Option<Vec<f64>>,
+    pub emissivity: Option<Vec<f64>>,
+    pub heat_capacity: Option<Vec<f64>>
+}
+
+fn parse_error(line: usize, reason: &'static str) -> Error {
+    Error::Parse{line, reason}
+}
+
+/// Read a CSV map of surface properties.
+///
+/// The header names the columns. `lon` and `lat` in degrees are required,
+/// and any of `albedo`, `emissivity` and `heat_capacity` may follow; other
+/// columns are ignored, so the output of `output::write_csv` can be
+/// extended into a map.
+pub fn read_map(path: &Path) -> Result<SurfaceMap,Error> {
+    let text = fs::read_to_string(path)?;
+    let mut lines = text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty() && !l.starts_with('#'));
+    let (_, header) = lines.next().ok_or(parse_error(1, "missing header"))?;
+    let names: Vec<&str> = header.split(',').map(|s| s.trim()).collect();
+    let column = |name: &str| names.iter().position(|&n| n == name);
+    let lon = column("lon").ok_or(parse_error(1, "missing lon column"))?;
+    let lat = column("lat").ok_or(parse_error(1, "missing lat column"))?;
+    let properties = [column("albedo"), column("emissivity"), column("heat_capacity")];
+    if properties.iter().all(|p| p.is_none()) {
+        return Err(parse_error(1, "no albedo, emissivity or heat_capacity column"));
+    }
+    let mut points = Vec::new();
+    let mut values: [Vec<f64>; 3] = Default::default();
+    for (n, line) in lines {
+        let line_no = n + 1;
+        let fields: Vec<f64> = line.split(',').map(|s| s.trim().parse().map_err(|_| parse_error(line_no, "invalid number"))).collect::<Result<_,_>>()?;
+        if fields.len() != names.len() {
+            return Err(parse_error(line_no, "wrong number of columns"));
+        }
+        points.push(coords::Coordinate::new(fields[lon].to_radians(), (90.0 - fields[lat]).to_radians())?);
+        for (p, v) in properties.iter().zip(values.iter_mut()) {
+            if let Some(p) = p { v.push(fields[*p]); }
+        }
+    }
+    let [albedo, emissivity, heat_capacity] = values;
+    let take = |p: Option<usize>, v: Vec<f64>| p.map(|_| v);
+    Ok(SurfaceMap{
+        points,
+        albedo: take(properties[0], albedo),
+        emissivity: take(properties[1], emissivity),
+        heat_capacity: take(properties[2], heat_capacity)
+    })
+}
+
+impl SurfaceMap {
+    /// Set the surface of every cell from the map.
+    ///
+    /// A cell takes the mean of the samples inside it, or the nearest sample
+    /// to its centroid if it contains none, so maps both finer and coarser
+    /// than the mesh work.
+    pub fn apply(self:&SurfaceMap, network: &mut grid::GridNetwork) -> Result<(),Error> {
+        if self.points.is_empty() {
+            return Err(Error::InvalidArgument("surface map has no samples"));
+        }
+        let mut members: Vec<Vec<usize>> = vec![Vec::new(); network.cells.len()];
+        {
+            let locator = grid::CellLocator::new(network)?;
+            let mut hint = None;
+            for (k, point) in self.points.iter().enumerate() {
+                if let Some(i) = locator.locate(point, hint)? {
+                    members[i].push(k);
+                    hint = Some(i);
+                }
+            }
+        }
+        for (cell, samples) in network.cells.iter_mut().zip(members.iter_mut()) {
+            if samples.is_empty() {
+                let center = cell.center();
+                let nearest = (0..self.points.len()).min_by(|&a, &b| center.angle_between(&self.points[a]).total_cmp(&center.angle_between(&self.points[b])));
+                samples.extend(nearest);
+            }
+            let mean = |v: &Vec<f64>| samples.iter().map(|&k| v[k]).sum::<f64>() / samples.len() as f64;
+            let mut surface = cell.surface;
+            if let Some(v) = &self.albedo { surface.albedo = mean(v); }
+            if let Some(v) = &self.emissivity { surface.emissivity = mean(v); }
+            if let Some(v) = &self.heat_capacity { surface.heat_capacity = mean(v); }
+            surface.check()?;
+            cell.surface = surface;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meshgen::MeshType;
+    use crate::pgen;
+
+    #[test]
+    fn test_map_and_function() {
+        let mut network = pgen::init_mesh(MeshType::LatLon(18, 36), pgen::InitialCondition::Constant(1.0)).unwrap();
+        // Ice caps poleward of 60 degrees
+        apply(&mut network, |lat, _, s| s.albedo = if lat.abs() > 60f64.to_radians() { 0.6 } else { 0.3 }).unwrap();
+        assert!(network.cells.iter().all(|c| c.surface.albedo == if lat_lon(c).0.abs() > 1.0 { 0.6 } else { 0.3 }));
+
+        // A map with a continent in the eastern hemisphere, sampled every 2.5 degrees
+        let path = std::env::temp_dir().join(format!("isosphere_surface_{}.csv", std::process::id()));
+        let mut text = String::from("lon,lat,heat_capacity\n");
+        for i in 0..144 {
+            for j in 0..72 {
+                let (lon, lat) = (-178.75 + 2.5 * i as f64, -88.75 + 2.5 * j as f64);
+                text += &format!("{},{},{}\n", lon, lat, if lon > 0.0 { 0.1 } else { 10.0 });
+            }
+        }
+        fs::write(&path, text).unwrap();
+        read_map(&path).unwrap().apply(&mut network).unwrap();
+        for cell in network.cells.iter() {
+            let expected = if lat_lon(cell).1.sin() > 0.0 { 0.1 } else { 10.0 };
+            assert!((cell.surface.heat_capacity - expected).abs() < 1e-12);
+            assert!(cell.surface.albedo == 0.3 || cell.surface.albedo == 0.6);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,191 @@
+//! VTK export for ParaView
+//!
+//! A `GridNetwork` is written as an ASCII unstructured grid (`.vtu`) with one
+//! polygon per cell, and a run as a collection (`.pvd`) of such files.
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use super::grid;
+
+/// VTK cell type of a general polygon
+static VTK_POLYGON: u8 = 7;
+
+/// Write the mesh of `network` with its value, other fields, area and centroid
+/// latitude and longitude (in degrees) as cell data
+pub fn write_vtu(network: &grid::GridNetwork, path: &Path) -> std::io::Result<()> {
+    let topology = network.topology();
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, r#"<?xml version="1.0"?>"#)?;
+    writeln!(out, r#"<VTKFile type="UnstructuredGrid" version="1.0" byte_order="LittleEndian" header_type="UInt64">"#)?;
+    writeln!(out, "<UnstructuredGrid>")?;
+    writeln!(out, r#"<Piece NumberOfPoints="{}" NumberOfCells="{}">"#, topology.vertices.len(), topology.faces.len())?;
+
+    writeln!(out, "<Points>")?;
+    writeln!(out, r#"<DataArray type="Float64" NumberOfComponents="3" format="ascii">"#)?;
+    for vertex in topology.vertices.iter() {
+        let (x, y, z) = vertex.cart().map_err(std::io::Error::other)?;
+        writeln!(out, "{} {} {}", x, y, z)?;
+    }
+    writeln!(out, "</DataArray>")?;
+    writeln!(out, "</Points>")?;
+
+    writeln!(out, "<Cells>")?;
+    writeln!(out, r#"<DataArray type="Int64" Name="connectivity" format="ascii">"#)?;
+    for face in topology.faces.iter() {
+        let ids: Vec<String> = face.iter().map(|v| v.to_string()).collect();
+        writeln!(out, "{}", ids.join(" "))?;
+    }
+    writeln!(out, "</DataArray>")?;
+    writeln!(out, r#"<DataArray type="Int64" Name="offsets" format="ascii">"#)?;
+    let mut offset = 0;
+    for face in topology.faces.iter() {
+        offset += face.len();
+        writeln!(out, "{}", offset)?;
+    }
+    writeln!(out, "</DataArray>")?;
+    writeln!(out, r#"<DataArray type="UInt8" Name="types" format="ascii">"#)?;
+    for _ in topology.faces.iter() {
+        writeln!(out, "{}", VTK_POLYGON)?;
+    }
+    writeln!(out, "</DataArray>")?;
+    writeln!(out, "</Cells>")?;
+
+    let centers: Vec<_> = network.cells.iter().map(|c| c.center()).collect();
+    writeln!(out, r#"<CellData Scalars="value">"#)?;
+    write_cell_array(&mut out, "value", network.cells.iter().map(|c| c.value))?;
+    for field in 1..network.n_fields() {
+        write_cell_array(&mut out, network.field_name(field), network.values(field).into_iter())?;
+    }
+    write_cell_array(&mut out, "area", network.cells.iter().map(|c| c.polygon.area()))?;
+    write_cell_array(&mut out, "lat", centers.iter().map(|c| 90.0 - c.theta.to_degrees()))?;
+    write_cell_array(&mut out, "lon", centers.iter().map(|c| c.phi.to_degrees()))?;
+    writeln!(out, "</CellData>")?;
+
+    writeln!(out, "</Piece>")?;
+    writeln!(out, "</UnstructuredGrid>")?;
+    writeln!(out, "</VTKFile>")?;
+    out.flush()
+}
+
+fn write_cell_array<W: Write>(out: &mut W, name: &str, values: impl Iterator<Item = f64>) -> std::io::Result<()> {
+    writeln!(out, r#"<DataArray type="Float64" Name="{}" format="ascii">"#, name)?;
+    for value in values {
+        writeln!(out, "{}", value)?;
+    }
+    writeln!(out, "</DataArray>")
+}
+
+/// A time series of VTU files listed in a PVD collection.
+///
+/// Each snapshot is written next to the collection as `<stem>_<step>.vtu`,
+/// and the collection is rewritten after every snapshot so that an
+/// interrupted run can still be opened.
+pub struct PvdWriter {
+    path: PathBuf,
+    entries: Vec<(f64, PathBuf)>,
+}
+
+impl PvdWriter {
+    pub fn new(path: &Path) -> PvdWriter {
+        PvdWriter { path: path.to_path_buf(), entries: Vec::new() }
+    }
+    /// Continue the collection at `path` from a run restarted at model time `time`.
+    ///
+    /// Snapshots from before `time` are kept; later ones were written after the
+    /// checkpoint the run restarts from and are dropped from the collection.
+    pub fn resume(path: &Path, time: f64) -> std::io::Result<PvdWriter> {
+        let mut writer = PvdWriter::new(path);
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(writer),
+            Err(e) => return Err(e),
+        };
+        let attribute = |line: &str, name: &str| -> Option<String> {
+            let start = line.find(&format!(r#" {}=""#, name))? + name.len() + 3;
+            let end = start + line[start..].find('"')?;
+            Some(line[start..end].to_string())
+        };
+        for line in text.lines().filter(|l| l.trim_start().starts_with("<DataSet")) {
+            let entry = attribute(line, "timestep").and_then(|t| t.parse::<f64>().ok()).zip(attribute(line, "file"));
+            let (t, file) = entry.ok_or_else(|| std::io::Error::other(format!("{} is not a collection written by isosphere", path.display())))?;
+            if t < time {
+                writer.entries.push((t, PathBuf::from(file)));
+            }
+        }
+        Ok(writer)
+    }
+    /// Number of snapshots written so far
+    pub fn len(self: &PvdWriter) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(self: &PvdWriter) -> bool {
+        self.entries.is_empty()
+    }
+    /// Write `network` as the snapshot at model time `time`, after `step` steps
+    pub fn write_step(self: &mut PvdWriter, network: &grid::GridNetwork, time: f64, step: usize) -> std::io::Result<()> {
+        let stem = self.path.file_stem().map_or(String::from("step"), |s| s.to_string_lossy().into_owned());
+        let name = PathBuf::from(format!("{}_{:06}.vtu", stem, step));
+        write_vtu(network, &self.path.with_file_name(&name))?;
+        self.entries.push((time, name));
+        self.write_collection()
+    }
+    fn write_collection(self: &PvdWriter) -> std::io::Result<()> {
+        // Write to a temporary file first so the collection is never left half written
+        let tmp = self.path.with_extension("pvd.tmp");
+        {
+            let mut out = BufWriter::new(File::create(&tmp)?);
+            writeln!(out, r#"<?xml version="1.0"?>"#)?;
+            writeln!(out, r#"<VTKFile type="Collection" version="1.0" byte_order="LittleEndian">"#)?;
+            writeln!(out, "<Collection>")?;
+            for (time, name) in self.entries.iter() {
+                writeln!(out, r#"<DataSet timestep="{}" part="0" file="{}"/>"#, time, name.display())?;
+            }
+            writeln!(out, "</Collection>")?;
+            writeln!(out, "</VTKFile>")?;
+            out.flush()?;
+        }
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meshgen::MeshType;
+    use crate::pgen;
+
+    #[test]
+    fn test_write_pvd() {
+        let dir = std::env::temp_dir().join(format!("isosphere_vtk_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let network = pgen::init_mesh(MeshType::Goldberg(1), pgen::InitialCondition::Radiative).unwrap();
+        let mut pvd = PvdWriter::new(&dir.join("run.pvd"));
+        pvd.write_step(&network, 0.0, 0).unwrap();
+        pvd.write_step(&network, 0.5, 10).unwrap();
+        assert_eq!(pvd.len(), 2);
+
+        let vtu = fs::read_to_string(dir.join("run_000010.vtu")).unwrap();
+        let topology = network.topology();
+        assert!(vtu.contains(&format!(r#"NumberOfPoints="{}" NumberOfCells="{}""#, topology.vertices.len(), network.cells.len())));
+        for name in ["value", "area", "lat", "lon"] {
+            assert!(vtu.contains(&format!(r#"Name="{}""#, name)));
+        }
+        let n_corners: usize = topology.faces.iter().map(|f| f.len()).sum();
+        assert!(vtu.contains(&format!("\n{}\n</DataArray>", n_corners)));
+
+        let collection = fs::read_to_string(dir.join("run.pvd")).unwrap();
+        assert!(collection.contains(r#"timestep="0.5" part="0" file="run_000010.vtu""#));
+
+        // A restart from time 0.25 keeps the first snapshot and carries on after it
+        let mut resumed = PvdWriter::resume(&dir.join("run.pvd"), 0.25).unwrap();
+        assert_eq!(resumed.len(), 1);
+        resumed.write_step(&network, 0.25, 5).unwrap();
+        let collection = fs::read_to_string(dir.join("run.pvd")).unwrap();
+        assert!(collection.contains(r#"timestep="0" part="0" file="run_000000.vtu""#));
+        assert!(collection.contains(r#"timestep="0.25" part="0" file="run_000005.vtu""#));
+        assert!(!collection.contains("run_000010.vtu"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}